@@ -0,0 +1,242 @@
+use crate::error::MaxTouchError;
+use crate::{protocol, read_payload, read_request, ConnectionState, REPORT_LENGTH};
+use serde::Serialize;
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
+use std::time::Instant;
+
+/// Number of read requests sent ahead of their responses by default.
+pub const DEFAULT_PIPELINE_DEPTH: usize = 4;
+
+/// Objects left out when every object is read. Reading T5 acknowledges the
+/// pending message, which would then never be seen.
+pub const UNREAD_OBJECTS: [u8; 1] = [5];
+
+/// A block of register memory, lengths may exceed a single report.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReadRange {
+    pub address: u16,
+    pub length: u16,
+}
+
+impl ReadRange {
+    fn end(&self) -> u32 {
+        self.address as u32 + self.length as u32
+    }
+
+    pub fn contains(&self, other: &ReadRange) -> bool {
+        other.address >= self.address && other.end() <= self.end()
+    }
+}
+
+/// Timings for a batched read, so the effect of merging and pipelining can be measured.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct BatchStats {
+    /// Ranges asked for.
+    pub ranges: usize,
+    /// Ranges actually read once adjacent and overlapping ones were merged.
+    pub merged_ranges: usize,
+    /// Read requests sent to the device, including retries.
+    pub requests: usize,
+    /// Bytes returned to the caller.
+    pub bytes: usize,
+    pub pipeline_depth: usize,
+    /// Times the pipeline lost sync and fell back to one request at a time.
+    pub fallbacks: usize,
+    pub elapsed_us: u64,
+}
+
+/// Raw contents of a set of objects, keyed by object type.
+#[derive(Serialize, Debug, Clone)]
+pub struct ObjectDump {
+    pub objects: HashMap<u8, Vec<u8>>,
+    pub stats: BatchStats,
+}
+
+/// Sort ranges and merge any which touch or overlap, so a run of objects
+/// packed back to back is read as one block. Fails with `OutOfBounds` if a
+/// block would be longer than a length can hold.
+pub fn merge_ranges(ranges: &[ReadRange]) -> Result<Vec<ReadRange>, MaxTouchError> {
+    let mut sorted: Vec<ReadRange> = ranges.iter().filter(|range| range.length > 0).copied().collect();
+    sorted.sort_by_key(|range| range.address);
+
+    let mut merged: Vec<ReadRange> = Vec::new();
+    for range in sorted {
+        match merged.last_mut() {
+            Some(last) if range.address as u32 <= last.end() => {
+                last.length = u16::try_from(cmp::max(last.end(), range.end()) - last.address as u32)
+                    .map_err(|_| MaxTouchError::OutOfBounds)?;
+            }
+            _ => merged.push(range),
+        }
+    }
+    Ok(merged)
+}
+
+struct Chunk {
+    block: usize,
+    offset: usize,
+    length: usize,
+    request: [u8; REPORT_LENGTH],
+}
+
+/// Read a set of ranges with as few round trips as possible. Ranges are
+/// merged, split into report sized requests and up to `pipeline_depth`
+/// requests are kept in flight. If a response goes missing or arrives out
+/// of order the requests in flight are resent one at a time with the usual
+/// retry policy. The data for each range is returned in the order given.
+pub fn read_ranges(connection: &ConnectionState, ranges: &[ReadRange], pipeline_depth: usize)
+    -> Result<(Vec<Vec<u8>>, BatchStats), MaxTouchError> {
+    let start = Instant::now();
+    let pipeline_depth = cmp::max(pipeline_depth, 1);
+    let merged = merge_ranges(ranges)?;
+    let mut stats = BatchStats {
+        ranges: ranges.len(),
+        merged_ranges: merged.len(),
+        pipeline_depth,
+        ..Default::default()
+    };
+
+    for range in &merged {
+        connection.check_access(range.address, range.length as usize)?;
+    }
+    let mut blocks: Vec<Vec<u8>> = merged.iter().map(|range| vec![0; range.length as usize]).collect();
+    let mut chunks = Vec::new();
+    for (block, range) in merged.iter().enumerate() {
+        for offset in (0..range.length as usize).step_by(REPORT_LENGTH - 5) {
+            let length = cmp::min(range.length as usize - offset, REPORT_LENGTH - 5);
+            chunks.push(Chunk {
+                block,
+                offset,
+                length,
+                request: read_request(range.address + offset as u16, length as u8),
+            });
+        }
+    }
+
+    let config = connection.protocol_config;
+    if config.drain {
+        connection.drain_input()?;
+    }
+    let mut next = 0;
+    let mut in_flight: VecDeque<&Chunk> = VecDeque::new();
+    while next < chunks.len() || !in_flight.is_empty() {
+        while next < chunks.len() && in_flight.len() < pipeline_depth {
+            connection.write_report(&chunks[next].request)?;
+            stats.requests += 1;
+            in_flight.push_back(&chunks[next]);
+            next += 1;
+        }
+
+        let chunk = in_flight.pop_front().unwrap();
+        let mut reply: [u8; REPORT_LENGTH] = [0; REPORT_LENGTH];
+        let size = connection.read_report(&mut reply, config.timeout_ms)?;
+        if size > 0 && protocol::response_matches(&chunk.request, &reply[..size]) {
+            let payload = read_payload(&reply, size, chunk.length)?;
+            blocks[chunk.block][chunk.offset..(chunk.offset + chunk.length)].copy_from_slice(payload);
+            continue;
+        }
+
+        // Out of sync, let the stragglers arrive and then redo everything
+        // which was in flight one request at a time.
+        println!("Batched read lost sync at 0x{:02x}{:02x}, falling back", chunk.request[3], chunk.request[2]);
+        stats.fallbacks += 1;
+        connection.drain_input()?;
+        for chunk in std::iter::once(chunk).chain(in_flight.drain(..)) {
+            let (reply, size) = connection.counted_transaction(&chunk.request, &mut stats.requests)?;
+            let payload = read_payload(&reply, size, chunk.length)?;
+            blocks[chunk.block][chunk.offset..(chunk.offset + chunk.length)].copy_from_slice(payload);
+        }
+    }
+
+    let mut results = Vec::new();
+    for range in ranges {
+        let block = merged.iter().position(|block| block.contains(range));
+        let data = match block {
+            Some(block) => {
+                let offset = (range.address - merged[block].address) as usize;
+                blocks[block][offset..(offset + range.length as usize)].to_vec()
+            }
+            // Only empty ranges are dropped by the merge.
+            None => Vec::new(),
+        };
+        stats.bytes += data.len();
+        results.push(data);
+    }
+    stats.elapsed_us = start.elapsed().as_micros() as u64;
+    Ok((results, stats))
+}
+
+/// Read every instance of the given objects in one batch.
+pub fn read_objects(connection: &ConnectionState, ids: &[u8], pipeline_depth: usize) -> Result<ObjectDump, MaxTouchError> {
+    let mut ranges = Vec::new();
+    for id in ids {
+        let object = connection.object_table.get(id).ok_or(MaxTouchError::ObjectNotFound(*id))?;
+        ranges.push(ReadRange {
+            address: object.address,
+            length: u16::try_from(object.length()).map_err(|_| MaxTouchError::OutOfBounds)?,
+        });
+    }
+    let (data, stats) = read_ranges(connection, &ranges, pipeline_depth)?;
+    Ok(ObjectDump {
+        objects: ids.iter().copied().zip(data).collect(),
+        stats,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{memory_connection, response, simulator_connection};
+
+    fn range(address: u16, length: u16) -> ReadRange {
+        ReadRange { address, length }
+    }
+
+    #[test]
+    fn merge_adjacent_and_overlapping_ranges() {
+        let ranges = [range(0x40, 8), range(0x10, 8), range(0x18, 4), range(0x1A, 10), range(0x20, 0), range(0x30, 2)];
+        assert_eq!(merge_ranges(&ranges), Ok(vec![range(0x10, 0x14), range(0x30, 2), range(0x40, 8)]));
+        // One range inside another.
+        assert_eq!(merge_ranges(&[range(0x10, 0x20), range(0x14, 4)]), Ok(vec![range(0x10, 0x20)]));
+    }
+
+    #[test]
+    fn merge_refuses_a_block_longer_than_a_length() {
+        assert_eq!(merge_ranges(&[range(0, 0x8000), range(0x8000, 0x8000)]), Err(MaxTouchError::OutOfBounds));
+    }
+
+    #[test]
+    fn pipelined_read_matches_unpipelined() {
+        let connection = simulator_connection();
+        let mut ids: Vec<u8> = connection.object_table.keys().copied().filter(|id| !UNREAD_OBJECTS.contains(id)).collect();
+        ids.sort();
+        let pipelined = read_objects(&connection, &ids, 4).unwrap();
+        let single = read_objects(&connection, &ids, 1).unwrap();
+        assert_eq!(pipelined.objects, single.objects);
+        assert_eq!(pipelined.stats.fallbacks, 0);
+        assert_eq!(pipelined.stats.requests, single.stats.requests);
+        // The objects are packed back to back, leaving T5 out splits them in two.
+        assert_eq!(pipelined.stats.merged_ranges, 2);
+    }
+
+    #[test]
+    fn out_of_order_response_falls_back() {
+        let (transport, connection) = memory_connection();
+        let first = read_request(0x100, 28);
+        let second = read_request(0x11C, 28);
+        // The second response overtakes the first, whose own response is stale
+        // by the time it arrives.
+        transport.push_response(&response(0, &second, &[2; 28]));
+        transport.push_response(&response(0, &first, &[1; 28]));
+        // One at a time, the first request is answered wrongly once and retried.
+        transport.push_response(&response(0, &second, &[2; 28]));
+        transport.push_response(&response(0, &first, &[1; 28]));
+        transport.push_response(&response(0, &second, &[2; 28]));
+
+        let (data, stats) = read_ranges(&connection, &[range(0x100, 56)], 2).unwrap();
+        assert_eq!(data, vec![[[1; 28], [2; 28]].concat()]);
+        assert_eq!(stats.fallbacks, 1);
+        assert_eq!(stats.requests, 5);
+    }
+}
@@ -0,0 +1,203 @@
+use crate::error::MaxTouchError;
+use crate::maxtouch::{InformationBlock, ObjectTableElement};
+use crate::protocol::{self, PacketSummary, ProtocolInfo};
+use crate::touch::TouchConfig;
+use crate::transport::Transport;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::time::Instant;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    /// Host to device.
+    Out,
+    /// Device to host.
+    In,
+}
+
+/// One report in a capture file. Captures are stored as JSON lines, one
+/// record per report, with the decoded header alongside the raw bytes so the
+/// file can be read without any tooling.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CaptureRecord {
+    pub timestamp_us: u64,
+    pub direction: Direction,
+    #[serde(flatten)]
+    pub summary: PacketSummary,
+    #[serde(with = "hex_bytes")]
+    pub data: Vec<u8>,
+}
+
+/// The state of the session when a capture started, stored on the first line
+/// of the file. Captures start on a connected session, so they do not contain
+/// the connect handshake, a replay is set up from this instead.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CaptureHeader {
+    pub protocol: ProtocolInfo,
+    pub info: InformationBlock,
+    /// The object table in the order the chip reports it, which sets the report IDs.
+    pub objects: Vec<ObjectTableElement>,
+    pub invert_x: bool,
+    pub invert_y: bool,
+    pub switch_xy: bool,
+    pub touch_config: TouchConfig,
+}
+
+/// The header line, wrapped so that it cannot be mistaken for a record.
+#[derive(Serialize, Deserialize)]
+struct HeaderLine {
+    header: CaptureHeader,
+}
+
+struct CaptureState {
+    writer: BufWriter<File>,
+    last_request: Option<Vec<u8>>,
+    records: usize,
+}
+
+/// Records every report sent to and received from a device.
+pub struct CaptureWriter {
+    start: Instant,
+    state: Mutex<CaptureState>,
+}
+
+impl CaptureWriter {
+    pub fn create(path: &Path, header: CaptureHeader) -> Result<Self, MaxTouchError> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writeln!(writer, "{}", serde_json::to_string(&HeaderLine { header })?)?;
+        Ok(CaptureWriter {
+            start: Instant::now(),
+            state: Mutex::new(CaptureState {
+                writer,
+                last_request: None,
+                records: 0,
+            }),
+        })
+    }
+
+    pub fn record(&self, direction: Direction, data: &[u8]) {
+        let mut state = self.state.lock();
+        // Responses do not carry the command, so decode them against the
+        // request they answer.
+        let summary = match direction {
+            Direction::Out => {
+                state.last_request = Some(data.to_vec());
+                protocol::summarize_request(data)
+            }
+            Direction::In => protocol::summarize_response(data, state.last_request.as_deref()),
+        };
+        let record = CaptureRecord {
+            timestamp_us: self.start.elapsed().as_micros() as u64,
+            direction,
+            summary,
+            data: data.to_vec(),
+        };
+        let written = serde_json::to_string(&record)
+            .map_err(MaxTouchError::from)
+            .and_then(|line| Ok(writeln!(state.writer, "{}", line)?));
+        match written {
+            Ok(_) => state.records += 1,
+            Err(e) => println!("Failed to write capture record: {}", e),
+        }
+    }
+
+    /// Flush the capture to disk, returning the number of reports recorded.
+    pub fn finish(self) -> Result<usize, MaxTouchError> {
+        let mut state = self.state.into_inner();
+        state.writer.flush()?;
+        Ok(state.records)
+    }
+}
+
+/// Read a capture. Captures written before the header existed have none.
+pub fn load(path: &Path) -> Result<(Option<CaptureHeader>, Vec<CaptureRecord>), MaxTouchError> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut header = None;
+    let mut records = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        if records.is_empty() && header.is_none() {
+            if let Ok(line) = serde_json::from_str::<HeaderLine>(&line) {
+                header = Some(line.header);
+                continue;
+            }
+        }
+        records.push(serde_json::from_str(&line)?);
+    }
+    Ok((header, records))
+}
+
+/// Transport which plays a capture back. Every report written must match the
+/// next outgoing report in the capture, reads return the recorded responses.
+/// A request which was not answered in the capture reads as a timeout.
+pub struct ReplayTransport {
+    records: Mutex<VecDeque<CaptureRecord>>,
+}
+
+impl ReplayTransport {
+    pub fn new(records: Vec<CaptureRecord>) -> Self {
+        ReplayTransport { records: Mutex::new(records.into()) }
+    }
+
+    pub fn open(path: &Path) -> Result<Self, MaxTouchError> {
+        Ok(Self::new(load(path)?.1))
+    }
+}
+
+impl Transport for ReplayTransport {
+    fn write(&self, data: &[u8]) -> Result<usize, MaxTouchError> {
+        let mut records = self.records.lock();
+        match records.pop_front() {
+            Some(record) if record.direction == Direction::Out && record.data == data => Ok(data.len()),
+            Some(record) => Err(MaxTouchError::ReplayMismatch(format!(
+                "expected {:?} {} at {}us, got {}",
+                record.direction,
+                hex_bytes::encode(&record.data),
+                record.timestamp_us,
+                hex_bytes::encode(data)
+            ))),
+            None => Err(MaxTouchError::ReplayMismatch("the capture has ended".to_string())),
+        }
+    }
+
+    fn read_timeout(&self, data: &mut [u8], _timeout: i32) -> Result<usize, MaxTouchError> {
+        let mut records = self.records.lock();
+        match records.front() {
+            Some(record) if record.direction == Direction::In => {
+                let record = records.pop_front().unwrap();
+                let size = record.data.len().min(data.len());
+                data[..size].copy_from_slice(&record.data[..size]);
+                Ok(size)
+            }
+            _ => Ok(0),
+        }
+    }
+}
+
+/// Reports are stored as space separated hex so that captures diff nicely.
+pub(crate) mod hex_bytes {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn encode(data: &[u8]) -> String {
+        data.iter().map(|byte| format!("{:02x}", byte)).collect::<Vec<_>>().join(" ")
+    }
+
+    pub fn serialize<S: Serializer>(data: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&encode(data))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        text.split_whitespace()
+            .map(|byte| u8::from_str_radix(byte, 16).map_err(serde::de::Error::custom))
+            .collect()
+    }
+}
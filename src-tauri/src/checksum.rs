@@ -0,0 +1,106 @@
+use serde::Serialize;
+
+const CRC24_POLY: u32 = 0x80001B;
+
+/// Objects holding messages, commands or diagnostic data rather than
+/// configuration. They are left out of the configuration checksum.
+pub const NON_CONFIG_OBJECTS: [u8; 4] = [5, 6, 37, 44];
+
+/// The contents of every instance of an object.
+pub struct ObjectImage<'a> {
+    pub object_type: u8,
+    pub address: u16,
+    pub data: &'a [u8],
+}
+
+/// Result of comparing the checksum the chip reports with one calculated
+/// from the registers read back from it.
+#[derive(Serialize, Debug, Clone)]
+pub struct ConfigChecksum {
+    pub device: u32,
+    pub local: u32,
+    pub matches: bool,
+}
+
+fn crc24_step(crc: u32, first: u8, second: u8) -> u32 {
+    let word = ((second as u32) << 8) | first as u32;
+    let result = (crc << 1) ^ word;
+    match result & 0x1000000 {
+        0 => result,
+        _ => result ^ CRC24_POLY,
+    }
+}
+
+/// The 24 bit CRC used by maXTouch chips. Bytes are consumed in little endian
+/// pairs, an odd trailing byte is padded with zero.
+pub fn crc24(data: &[u8]) -> u32 {
+    let mut crc = 0;
+    for pair in data.chunks(2) {
+        crc = crc24_step(crc, pair[0], pair.get(1).copied().unwrap_or(0));
+    }
+    crc & 0xFFFFFF
+}
+
+/// Checksum of the configuration the way the chip calculates it: over memory
+/// from the start of T71 (or T7 when there is no T71) to the end of the last
+/// object. Gaps and non-configuration objects count as zero. Returns `None`
+/// when neither object is present.
+pub fn config_crc(objects: &[ObjectImage]) -> Option<u32> {
+    let start = objects.iter()
+        .find(|object| object.object_type == 71)
+        .or_else(|| objects.iter().find(|object| object.object_type == 7))?
+        .address as usize;
+    let end = objects.iter()
+        .map(|object| object.address as usize + object.data.len())
+        .max()?;
+
+    let mut memory = vec![0u8; end.saturating_sub(start)];
+    for object in objects {
+        if NON_CONFIG_OBJECTS.contains(&object.object_type) {
+            continue;
+        }
+        for (index, byte) in object.data.iter().enumerate() {
+            let address = object.address as usize + index;
+            if address >= start {
+                memory[address - start] = *byte;
+            }
+        }
+    }
+    Some(crc24(&memory))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Expected values come from mxt_calculate_crc in the Linux atmel_mxt_ts driver.
+
+    #[test]
+    fn crc24_known_answers() {
+        assert_eq!(crc24(&[]), 0);
+        assert_eq!(crc24(&[0x01, 0x00]), 0x000001);
+        // An odd trailing byte is paired with zero.
+        assert_eq!(crc24(&[0x01]), 0x000001);
+        assert_eq!(crc24(b"123456789"), 0x022A0B);
+        let counting: Vec<u8> = (0..=255).collect();
+        assert_eq!(crc24(&counting), 0xEF3F62);
+    }
+
+    #[test]
+    fn config_crc_zeroes_gaps_and_non_config_objects() {
+        let objects = [
+            ObjectImage { object_type: 7, address: 0x100, data: &[0x20, 0x10, 0x32, 0x43] },
+            ObjectImage { object_type: 5, address: 0x104, data: &[0xAA, 0xBB, 0xCC] },
+            ObjectImage { object_type: 8, address: 0x10A, data: &[0x84, 0x00, 0x0A] },
+            // Before the start of T7, so not included.
+            ObjectImage { object_type: 38, address: 0x0F0, data: &[0xFF; 8] },
+        ];
+        assert_eq!(config_crc(&objects), Some(0x0C6F42));
+    }
+
+    #[test]
+    fn config_crc_needs_t7_or_t71() {
+        let objects = [ObjectImage { object_type: 8, address: 0x100, data: &[1, 2, 3] }];
+        assert_eq!(config_crc(&objects), None);
+    }
+}
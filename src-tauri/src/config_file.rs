@@ -0,0 +1,464 @@
+use crate::batch;
+use crate::capture::hex_bytes;
+use crate::checksum::{self, ObjectImage, NON_CONFIG_OBJECTS};
+use crate::error::MaxTouchError;
+use crate::maxtouch::InformationBlock;
+use crate::{decode_object, obp_raw, write_object_impl, xcfg, ConnectionState, DecodedObject};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// Identifies configuration files written by this tool.
+pub const FORMAT: &str = "maxtouch-debug-config";
+/// Bumped whenever the layout of the file changes.
+pub const VERSION: u32 = 1;
+
+/// A snapshot of the sensor configuration. The file describes the chip it
+/// was taken from, so it can be checked against the chip it is loaded onto.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ConfigFile {
+    pub format: String,
+    pub version: u32,
+    pub info: InformationBlock,
+    /// The object table, in address order, with the contents of each object.
+    pub objects: Vec<ObjectConfig>,
+}
+
+impl ConfigFile {
+    /// Checksum of the configuration objects, as the chip would report it
+    /// once the file has been applied.
+    pub fn config_crc(&self) -> Option<u32> {
+        // Objects which were not saved count as zero, like gaps.
+        let contents: Vec<Vec<u8>> = self.objects.iter()
+            .map(|object| match object.data.is_empty() {
+                true => vec![0; object.size as usize * object.instances as usize],
+                false => object.data.iter().flat_map(|data| data.raw.iter().copied()).collect(),
+            })
+            .collect();
+        let images: Vec<ObjectImage> = self.objects.iter().zip(&contents)
+            .map(|(object, data)| ObjectImage { object_type: object.object_type, address: object.address, data })
+            .collect();
+        checksum::config_crc(&images)
+    }
+
+    /// Checksum of the information block and object table.
+    pub fn info_crc(&self) -> u32 {
+        let mut data = vec![self.info.family_id, self.info.variant_id, self.info.version, self.info.build,
+            self.info.matrix_x_size, self.info.matrix_y_size, self.info.num_objects];
+        for object in &self.objects {
+            data.extend_from_slice(&[
+                object.object_type,
+                object.address as u8,
+                (object.address >> 8) as u8,
+                (object.size - 1) as u8,
+                (object.instances - 1) as u8,
+                object.report_ids_per_instance,
+            ]);
+        }
+        checksum::crc24(&data)
+    }
+}
+
+/// The contents of one object instance, as found in files which do not carry
+/// the object table.
+pub struct InstanceContents {
+    pub object_type: u8,
+    pub instance: u16,
+    pub data: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ObjectConfig {
+    pub object_type: u8,
+    pub address: u16,
+    pub size: u16,
+    pub instances: u16,
+    pub report_ids_per_instance: u8,
+    /// One entry per instance, empty for objects which are not saved.
+    pub data: Vec<InstanceConfig>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct InstanceConfig {
+    pub instance: u16,
+    #[serde(with = "hex_bytes")]
+    pub raw: Vec<u8>,
+    /// Decoded registers, for reading only. `raw` is what gets written back.
+    #[serde(default, skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub fields: Option<DecodedObject>,
+}
+
+/// An instance which read back differently to what was written.
+#[derive(Serialize, Debug, Clone)]
+pub struct InstanceMismatch {
+    pub object_type: u8,
+    pub instance: u16,
+    /// Offsets of the registers which differ.
+    pub offsets: Vec<u16>,
+}
+
+/// Outcome of applying a configuration.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct LoadReport {
+    pub objects_written: usize,
+    pub instances_written: usize,
+    pub mismatches: Vec<InstanceMismatch>,
+}
+
+/// Read every instance of every object.
+pub fn read_config(connection: &ConnectionState) -> Result<ConfigFile, MaxTouchError> {
+    let info = connection.info.clone().ok_or(MaxTouchError::NotConnected)?;
+    let mut table: Vec<_> = connection.object_table.iter().collect();
+    table.sort_by_key(|(_, object)| object.address);
+
+    let ids: Vec<u8> = table.iter()
+        .map(|(id, _)| **id)
+        .filter(|id| !batch::UNREAD_OBJECTS.contains(id))
+        .collect();
+    let dump = batch::read_objects(connection, &ids, batch::DEFAULT_PIPELINE_DEPTH)?;
+
+    let objects = table.into_iter().map(|(id, object)| {
+        let data = match dump.objects.get(id) {
+            Some(data) => data.chunks(object.size as usize)
+                .enumerate()
+                .map(|(instance, raw)| InstanceConfig {
+                    instance: instance as u16,
+                    raw: raw.to_vec(),
+                    fields: decode_object(*id, raw).ok(),
+                })
+                .collect(),
+            None => Vec::new(),
+        };
+        ObjectConfig {
+            object_type: *id,
+            address: object.address,
+            size: object.size,
+            instances: object.instances,
+            report_ids_per_instance: object.report_ids,
+            data,
+        }
+    }).collect();
+
+    Ok(ConfigFile {
+        format: FORMAT.to_string(),
+        version: VERSION,
+        info,
+        objects,
+    })
+}
+
+/// Map instance contents read from a file onto the object table of the
+/// connected chip. Contents of the wrong size are zero padded or truncated,
+/// like the Linux driver does.
+pub fn assemble(connection: &ConnectionState, info: InformationBlock, contents: Vec<InstanceContents>) -> Result<ConfigFile, MaxTouchError> {
+    let mut table: Vec<_> = connection.object_table.iter().collect();
+    table.sort_by_key(|(_, object)| object.address);
+    let mut objects: Vec<ObjectConfig> = table.into_iter()
+        .map(|(id, object)| ObjectConfig {
+            object_type: *id,
+            address: object.address,
+            size: object.size,
+            instances: object.instances,
+            report_ids_per_instance: object.report_ids,
+            data: Vec::new(),
+        })
+        .collect();
+
+    for mut instance in contents {
+        let object = objects.iter_mut()
+            .find(|object| object.object_type == instance.object_type)
+            .ok_or_else(|| MaxTouchError::ConfigMismatch(format!("T{} is not on the device", instance.object_type)))?;
+        if instance.instance >= object.instances {
+            return Err(MaxTouchError::ConfigMismatch(format!("T{} has no instance {}", object.object_type, instance.instance)));
+        }
+        if instance.data.len() != object.size as usize {
+            println!("T{} instance {} is {} bytes, the device has {}", object.object_type, instance.instance,
+                instance.data.len(), object.size);
+            instance.data.resize(object.size as usize, 0);
+        }
+        object.data.retain(|data| data.instance != instance.instance);
+        object.data.push(InstanceConfig {
+            instance: instance.instance,
+            fields: decode_object(object.object_type, &instance.data).ok(),
+            raw: instance.data,
+        });
+        object.data.sort_by_key(|data| data.instance);
+    }
+
+    Ok(ConfigFile {
+        format: FORMAT.to_string(),
+        version: VERSION,
+        info,
+        objects,
+    })
+}
+
+/// The configuration of a chip with every register cleared, which is how a
+/// chip starts out before it is configured.
+pub fn defaults(connection: &ConnectionState) -> Result<ConfigFile, MaxTouchError> {
+    let info = connection.info.clone().ok_or(MaxTouchError::NotConnected)?;
+    let contents = connection.object_table.iter()
+        .filter(|(id, _)| !batch::UNREAD_OBJECTS.contains(id))
+        .flat_map(|(id, object)| (0..object.instances).map(move |instance| InstanceContents {
+            object_type: *id,
+            instance,
+            data: vec![0; object.size as usize],
+        }))
+        .collect();
+    assemble(connection, info, contents)
+}
+
+/// Where a configuration to compare comes from.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(tag = "source", rename_all = "snake_case")]
+pub enum ConfigSource {
+    Device,
+    File { path: PathBuf },
+    Defaults,
+}
+
+impl ConfigSource {
+    pub fn read(&self, connection: &ConnectionState) -> Result<ConfigFile, MaxTouchError> {
+        match self {
+            ConfigSource::Device => read_config(connection),
+            ConfigSource::File { path } => load(path, connection),
+            ConfigSource::Defaults => defaults(connection),
+        }
+    }
+
+    /// Read the source if it does not need a device, `None` if it does.
+    pub fn read_without_device(&self) -> Option<Result<ConfigFile, MaxTouchError>> {
+        match self {
+            ConfigSource::File { path } if !needs_device(path) => Some(load_json(path)),
+            _ => None,
+        }
+    }
+}
+
+fn extension(path: &Path) -> Option<String> {
+    path.extension().and_then(|extension| extension.to_str()).map(str::to_ascii_lowercase)
+}
+
+/// Whether loading the file needs the object table of a connected chip.
+fn needs_device(path: &Path) -> bool {
+    matches!(extension(path).as_deref(), Some("raw" | "xcfg"))
+}
+
+/// Save a configuration. The format follows the extension: `.raw` and `.xcfg`
+/// are the Microchip formats, anything else is this tool's JSON.
+pub fn save(path: &Path, config: &ConfigFile) -> Result<(), MaxTouchError> {
+    match extension(path).as_deref() {
+        Some("raw") => fs::write(path, obp_raw::write(config))?,
+        Some("xcfg") => fs::write(path, xcfg::write(config))?,
+        _ => {
+            let mut writer = BufWriter::new(File::create(path)?);
+            serde_json::to_writer_pretty(&mut writer, config)?;
+            writer.flush()?;
+        }
+    }
+    Ok(())
+}
+
+/// Load a configuration saved in any of the formats `save` writes. The
+/// Microchip formats do not carry the whole object table, so they are mapped
+/// onto the table of the connected chip.
+pub fn load(path: &Path, connection: &ConnectionState) -> Result<ConfigFile, MaxTouchError> {
+    match extension(path).as_deref() {
+        Some("raw") => obp_raw::read(&fs::read_to_string(path)?, connection),
+        Some("xcfg") => xcfg::read(&fs::read_to_string(path)?, connection),
+        _ => load_json(path),
+    }
+}
+
+/// Load a configuration in this tool's JSON format, which describes the
+/// chip itself and so does not need one connected.
+pub fn load_json(path: &Path) -> Result<ConfigFile, MaxTouchError> {
+    let config: ConfigFile = serde_json::from_reader(BufReader::new(File::open(path)?))?;
+    if config.format != FORMAT {
+        return Err(MaxTouchError::Encoding(format!("{} is not a configuration file", path.display())));
+    }
+    if config.version > VERSION {
+        return Err(MaxTouchError::Encoding(format!("Configuration file version {} is newer than {}", config.version, VERSION)));
+    }
+    Ok(config)
+}
+
+/// Check that a configuration was taken from the same kind of chip, running
+/// the same firmware, with the same object layout.
+pub fn check_compatible(connection: &ConnectionState, config: &ConfigFile) -> Result<(), MaxTouchError> {
+    let info = connection.info.as_ref().ok_or(MaxTouchError::NotConnected)?;
+    let chip = (info.family_id, info.variant_id, info.version, info.build);
+    let file = (config.info.family_id, config.info.variant_id, config.info.version, config.info.build);
+    if chip != file {
+        return Err(MaxTouchError::ConfigMismatch(format!(
+            "file is for family {:#04x} variant {:#04x} firmware {:#04x} build {:#04x}, device is family {:#04x} variant {:#04x} firmware {:#04x} build {:#04x}",
+            file.0, file.1, file.2, file.3, chip.0, chip.1, chip.2, chip.3)));
+    }
+    if config.objects.len() != connection.object_table.len() {
+        return Err(MaxTouchError::ConfigMismatch(format!("file has {} objects, device has {}",
+            config.objects.len(), connection.object_table.len())));
+    }
+    for object in &config.objects {
+        let layout = connection.object_table.get(&object.object_type)
+            .map(|details| (details.address, details.size, details.instances));
+        if layout != Some((object.address, object.size, object.instances)) {
+            return Err(MaxTouchError::ConfigMismatch(format!("T{} is laid out differently", object.object_type)));
+        }
+        if object.data.iter().any(|data| data.instance >= object.instances || data.raw.len() != object.size as usize) {
+            return Err(MaxTouchError::ConfigMismatch(format!("T{} has malformed contents", object.object_type)));
+        }
+    }
+    Ok(())
+}
+
+/// Write every configuration object in the file, then read them all back and
+/// compare. Message, command and diagnostic objects are never written.
+pub fn apply(connection: &ConnectionState, config: &ConfigFile) -> Result<LoadReport, MaxTouchError> {
+    check_compatible(connection, config)?;
+    let objects: Vec<&ObjectConfig> = config.objects.iter()
+        .filter(|object| !object.data.is_empty() && !NON_CONFIG_OBJECTS.contains(&object.object_type))
+        .collect();
+
+    let mut report = LoadReport::default();
+    for object in &objects {
+        for data in &object.data {
+            write_object_impl(connection, object.object_type, data.instance, &data.raw)?;
+            report.instances_written += 1;
+        }
+        report.objects_written += 1;
+    }
+
+    let ids: Vec<u8> = objects.iter().map(|object| object.object_type).collect();
+    let dump = batch::read_objects(connection, &ids, batch::DEFAULT_PIPELINE_DEPTH)?;
+    for object in &objects {
+        let read_back = dump.objects.get(&object.object_type).ok_or(MaxTouchError::ShortRead)?;
+        for data in &object.data {
+            let start = data.instance as usize * object.size as usize;
+            let actual = read_back.get(start..start + data.raw.len()).ok_or(MaxTouchError::ShortRead)?;
+            let offsets: Vec<u16> = data.raw.iter().zip(actual)
+                .enumerate()
+                .filter(|(_, (expected, actual))| expected != actual)
+                .map(|(offset, _)| offset as u16)
+                .collect();
+            if !offsets.is_empty() {
+                report.mismatches.push(InstanceMismatch {
+                    object_type: object.object_type,
+                    instance: data.instance,
+                    offsets,
+                });
+            }
+        }
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::simulator::Simulator;
+    use crate::tests::simulator_connection;
+    use crate::transport::Transport;
+    use crate::{initialize_device, MaxTouchCommand};
+
+    /// A simulator with one register which ignores writes.
+    struct StuckRegister {
+        simulator: Simulator,
+        address: u16,
+    }
+
+    impl Transport for StuckRegister {
+        fn write(&self, data: &[u8]) -> Result<usize, MaxTouchError> {
+            let mut data = data.to_vec();
+            let start = u16::from_le_bytes([data[2], data[3]]);
+            if data[1] == MaxTouchCommand::Write as u8 && (start..start + data[4] as u16).contains(&self.address) {
+                data[5 + (self.address - start) as usize] = 0;
+            }
+            self.simulator.write(&data)
+        }
+
+        fn read_timeout(&self, data: &mut [u8], timeout: i32) -> Result<usize, MaxTouchError> {
+            self.simulator.read_timeout(data, timeout)
+        }
+    }
+
+    /// Write the configuration of a simulator with some registers changed in
+    /// another format and read it back, which must give the same contents.
+    pub(crate) fn assert_round_trip(write: fn(&ConfigFile) -> String,
+        read: fn(&str, &ConnectionState) -> Result<ConfigFile, MaxTouchError>) {
+        let connection = simulator_connection();
+        write_object_impl(&connection, 7, 0, &[32, 10, 50]).unwrap();
+        write_object_impl(&connection, 8, 0, &[0x84, 0x00, 0x0A]).unwrap();
+        write_object_impl(&connection, 100, 1, &[0x83, 0x00, 0x12]).unwrap();
+        let config = read_config(&connection).unwrap();
+        let loaded = read(&write(&config), &connection).unwrap();
+
+        let chip = |config: &ConfigFile| (config.info.family_id, config.info.variant_id, config.info.version, config.info.build);
+        let contents = |config: &ConfigFile| -> Vec<(u8, u16, Vec<u8>)> {
+            config.objects.iter()
+                .filter(|object| !NON_CONFIG_OBJECTS.contains(&object.object_type))
+                .flat_map(|object| object.data.iter().map(|data| (object.object_type, data.instance, data.raw.clone())))
+                .collect()
+        };
+        assert_eq!(chip(&loaded), chip(&config));
+        assert_eq!(contents(&loaded), contents(&config));
+        assert_eq!(loaded.config_crc(), config.config_crc());
+    }
+
+    fn config_error(result: Result<LoadReport, MaxTouchError>) -> String {
+        match result {
+            Err(MaxTouchError::ConfigMismatch(message)) => message,
+            other => panic!("expected a mismatch, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn apply_rejects_a_different_chip() {
+        let connection = simulator_connection();
+        let config = read_config(&connection).unwrap();
+        for change in 0..4 {
+            let mut other = config.clone();
+            match change {
+                0 => other.info.family_id += 1,
+                1 => other.info.variant_id += 1,
+                2 => other.info.version += 1,
+                _ => other.info.build += 1,
+            }
+            assert!(config_error(apply(&connection, &other)).starts_with("file is for family"));
+        }
+    }
+
+    #[test]
+    fn apply_rejects_a_different_layout() {
+        let connection = simulator_connection();
+        let config = read_config(&connection).unwrap();
+
+        let mut moved = config.clone();
+        moved.objects.iter_mut().find(|object| object.object_type == 7).unwrap().address += 1;
+        assert_eq!(config_error(apply(&connection, &moved)), "T7 is laid out differently");
+
+        let mut missing = config.clone();
+        missing.objects.retain(|object| object.object_type != 38);
+        assert!(config_error(apply(&connection, &missing)).contains("objects, device has"));
+
+        let mut truncated = config.clone();
+        truncated.objects.iter_mut().find(|object| object.object_type == 8).unwrap().data[0].raw.pop();
+        assert_eq!(config_error(apply(&connection, &truncated)), "T8 has malformed contents");
+    }
+
+    #[test]
+    fn apply_reports_registers_which_do_not_read_back() {
+        let t7 = simulator_connection().object_table[&7].address;
+        let mut connection = ConnectionState::new(Box::new(StuckRegister { simulator: Simulator::new(), address: t7 + 1 }));
+        initialize_device(&mut connection).unwrap();
+
+        let mut config = read_config(&connection).unwrap();
+        let t7_config = config.objects.iter_mut().find(|object| object.object_type == 7).unwrap();
+        t7_config.data[0].raw[..3].copy_from_slice(&[32, 10, 50]);
+        let report = apply(&connection, &config).unwrap();
+        assert!(report.objects_written > 0);
+        assert_eq!(report.mismatches.len(), 1);
+        let mismatch = &report.mismatches[0];
+        assert_eq!((mismatch.object_type, mismatch.instance, &mismatch.offsets[..]), (7, 0, &[1][..]));
+    }
+}
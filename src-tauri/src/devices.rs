@@ -0,0 +1,132 @@
+use crate::error::MaxTouchError;
+use hidapi::{DeviceInfo, HidApi};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+const QMK_VENDOR_ID: u16 = 0xFEED;
+const QMK_PRODUCT_ID: u16 = 0x0000;
+const RAW_HID_USAGE_PAGE: u16 = 0xFF60;
+const RAW_HID_USAGE: u16 = 0x61;
+
+/// The file the device filter is loaded from, relative to the app config directory.
+pub const DEVICE_FILTER_FILE: &str = "devices.json";
+
+/// Matches a HID interface. Fields which are not set match anything.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct DeviceMatcher {
+    pub vendor_id: Option<u16>,
+    pub product_id: Option<u16>,
+    pub usage_page: Option<u16>,
+    pub usage: Option<u16>,
+    pub serial_number: Option<String>,
+}
+
+impl DeviceMatcher {
+    /// A matcher which identifies this particular interface, even if it
+    /// comes back on a different path after being replugged.
+    pub fn for_device(device: &DeviceInfo) -> Self {
+        DeviceMatcher {
+            vendor_id: Some(device.vendor_id()),
+            product_id: Some(device.product_id()),
+            usage_page: Some(device.usage_page()),
+            usage: Some(device.usage()),
+            serial_number: device.serial_number().map(String::from),
+        }
+    }
+
+    pub fn matches(&self, device: &DeviceInfo) -> bool {
+        self.vendor_id.is_none_or(|id| id == device.vendor_id())
+            && self.product_id.is_none_or(|id| id == device.product_id())
+            && self.usage_page.is_none_or(|page| page == device.usage_page())
+            && self.usage.is_none_or(|usage| usage == device.usage())
+            && self.serial_number.as_ref().is_none_or(|serial| Some(serial.as_str()) == device.serial_number())
+    }
+}
+
+/// A list of matchers, an interface is a candidate if any of them match.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DeviceFilter {
+    pub matchers: Vec<DeviceMatcher>,
+}
+
+impl Default for DeviceFilter {
+    fn default() -> Self {
+        // The QMK defaults used by the debug keymap.
+        DeviceFilter {
+            matchers: vec![DeviceMatcher {
+                vendor_id: Some(QMK_VENDOR_ID),
+                product_id: Some(QMK_PRODUCT_ID),
+                usage_page: Some(RAW_HID_USAGE_PAGE),
+                usage: Some(RAW_HID_USAGE),
+                serial_number: None,
+            }],
+        }
+    }
+}
+
+impl DeviceFilter {
+    pub fn load(path: &Path) -> Result<Self, MaxTouchError> {
+        let contents = fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&contents)?)
+    }
+
+    /// Load the filter from `path`, falling back to the QMK defaults if the
+    /// file does not exist or can not be parsed.
+    pub fn load_or_default(path: &Path) -> Self {
+        if !path.exists() {
+            return Default::default();
+        }
+        match Self::load(path) {
+            Ok(filter) => filter,
+            Err(e) => {
+                println!("Ignoring device filter {}: {}", path.display(), e);
+                Default::default()
+            }
+        }
+    }
+
+    pub fn matches(&self, device: &DeviceInfo) -> bool {
+        self.matchers.iter().any(|matcher| matcher.matches(device))
+    }
+}
+
+/// A raw HID interface which could be a trackpad running the debug keymap.
+#[derive(Serialize, Debug, Clone)]
+pub struct DeviceDescriptor {
+    pub path: String,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub usage_page: u16,
+    pub usage: u16,
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+    pub serial_number: Option<String>,
+    pub matches_filter: bool,
+}
+
+impl DeviceDescriptor {
+    pub fn new(device: &DeviceInfo, filter: &DeviceFilter) -> Self {
+        DeviceDescriptor {
+            path: device.path().to_string_lossy().into_owned(),
+            vendor_id: device.vendor_id(),
+            product_id: device.product_id(),
+            usage_page: device.usage_page(),
+            usage: device.usage(),
+            manufacturer: device.manufacturer_string().map(String::from),
+            product: device.product_string().map(String::from),
+            serial_number: device.serial_number().map(String::from),
+            matches_filter: filter.matches(device),
+        }
+    }
+}
+
+/// List every interface on a vendor defined usage page, which is where QMK
+/// puts raw HID. Interfaces matching the filter are flagged.
+pub fn enumerate(api: &HidApi, filter: &DeviceFilter) -> Vec<DeviceDescriptor> {
+    api.device_list()
+        .filter(|device| device.usage_page() >= 0xFF00)
+        .map(|device| DeviceDescriptor::new(device, filter))
+        .collect()
+}
@@ -0,0 +1,118 @@
+use crate::checksum::NON_CONFIG_OBJECTS;
+use crate::config_file::{ConfigFile, ObjectConfig};
+use crate::decode_object;
+use crate::layout;
+use serde::Serialize;
+use serde_json::Value;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeKind {
+    Added,
+    Removed,
+    Changed,
+}
+
+/// One field which differs between two configurations.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct FieldChange {
+    pub object_type: u8,
+    pub instance: u16,
+    /// Field name from `maxtouch.rs`, array elements as `name[index]`.
+    /// Registers of objects without a struct are named `byte[offset]`.
+    pub field: String,
+    pub kind: ChangeKind,
+    pub old: Option<Value>,
+    pub new: Option<Value>,
+}
+
+/// The fields of an instance in register order, with their values.
+fn instance_fields(object_type: u8, size: u16, raw: &[u8]) -> Vec<(String, Value)> {
+    let decoded = decode_object(object_type, raw).ok()
+        .and_then(|decoded| Some((layout::layout(&decoded).ok()?, serde_json::to_value(&decoded).ok()?)));
+    match decoded {
+        Some((layout, values)) => layout.into_iter()
+            .filter(|field| field.offset + field.width as u16 <= size)
+            .map(|field| {
+                let value = match field.name.split_once('[') {
+                    Some((name, index)) => {
+                        let index: usize = index.trim_end_matches(']').parse().unwrap_or(0);
+                        values[name][index].clone()
+                    }
+                    None => values[&field.name].clone(),
+                };
+                (field.name, value)
+            })
+            .collect(),
+        None => raw.iter()
+            .enumerate()
+            .map(|(offset, byte)| (format!("byte[{}]", offset), Value::from(*byte)))
+            .collect(),
+    }
+}
+
+fn object_instances(object: Option<&ObjectConfig>) -> Vec<(u16, Vec<(String, Value)>)> {
+    object.map(|object| {
+        object.data.iter()
+            .map(|data| (data.instance, instance_fields(object.object_type, object.size, &data.raw)))
+            .collect()
+    }).unwrap_or_default()
+}
+
+/// Compare two configurations field by field. Objects and instances only in
+/// `new` are reported as added fields, those only in `old` as removed.
+/// Message, command and diagnostic objects are ignored.
+pub fn diff(old: &ConfigFile, new: &ConfigFile) -> Vec<FieldChange> {
+    let mut object_types: Vec<u8> = old.objects.iter().chain(&new.objects)
+        .map(|object| object.object_type)
+        .filter(|object_type| !NON_CONFIG_OBJECTS.contains(object_type))
+        .collect();
+    object_types.sort();
+    object_types.dedup();
+
+    let mut changes = Vec::new();
+    for object_type in object_types {
+        let old_instances = object_instances(old.objects.iter().find(|object| object.object_type == object_type));
+        let new_instances = object_instances(new.objects.iter().find(|object| object.object_type == object_type));
+        let mut instances: Vec<u16> = old_instances.iter().chain(&new_instances).map(|(instance, _)| *instance).collect();
+        instances.sort();
+        instances.dedup();
+
+        for instance in instances {
+            let old_fields = old_instances.iter().find(|(other, _)| *other == instance).map(|(_, fields)| fields);
+            let new_fields = new_instances.iter().find(|(other, _)| *other == instance).map(|(_, fields)| fields);
+            let mut names: Vec<&String> = old_fields.into_iter().chain(new_fields).flatten().map(|(name, _)| name).collect();
+            // Keep register order, dropping the names both sides have.
+            let mut seen = Vec::new();
+            names.retain(|name| match seen.contains(name) {
+                true => false,
+                false => {
+                    seen.push(*name);
+                    true
+                }
+            });
+
+            for name in names {
+                let find = |fields: Option<&Vec<(String, Value)>>| {
+                    fields.and_then(|fields| fields.iter().find(|(other, _)| other == name)).map(|(_, value)| value.clone())
+                };
+                let (old_value, new_value) = (find(old_fields), find(new_fields));
+                let kind = match (&old_value, &new_value) {
+                    (None, Some(_)) => ChangeKind::Added,
+                    (Some(_), None) => ChangeKind::Removed,
+                    (Some(old_value), Some(new_value)) if old_value != new_value => ChangeKind::Changed,
+                    _ => continue,
+                };
+                changes.push(FieldChange {
+                    object_type,
+                    instance,
+                    field: name.clone(),
+                    kind,
+                    old: old_value,
+                    new: new_value,
+                });
+            }
+        }
+    }
+    changes
+}
@@ -0,0 +1,89 @@
+use serde::Serialize;
+use std::fmt;
+
+/// Errors returned by the device layer and the Tauri commands. These are
+/// serialized as `{ "type": "...", "details": ... }` so the frontend can tell
+/// the failure modes apart.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", content = "details")]
+pub enum MaxTouchError {
+    NotConnected,
+    SessionNotFound(String),
+    SessionRequired,
+    DeviceNotFound,
+    Timeout,
+    Cancelled,
+    DeviceStatus(u8),
+    ShortRead,
+    ResponseMismatch,
+    ObjectNotFound(u8),
+    UnsupportedObject(u8),
+    UnsupportedCommand(String),
+    OutOfBounds,
+    VersionMismatch { expected: u16, got: u16 },
+    Hid(String),
+    Encoding(String),
+    Io(String),
+    ReplayMismatch(String),
+    ConfirmationRequired,
+    CommandFailed(String),
+    ConfigMismatch(String),
+    NoConfigObjects,
+}
+
+impl fmt::Display for MaxTouchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MaxTouchError::NotConnected => write!(f, "Not connected."),
+            MaxTouchError::SessionNotFound(session) => write!(f, "No session for {}", session),
+            MaxTouchError::SessionRequired => write!(f, "Several devices are connected, a session must be given"),
+            MaxTouchError::DeviceNotFound => write!(f, "No device found"),
+            MaxTouchError::Timeout => write!(f, "Timed out waiting for the device"),
+            MaxTouchError::Cancelled => write!(f, "The request was cancelled"),
+            MaxTouchError::DeviceStatus(status) => write!(f, "Device reported an error ({})", status),
+            MaxTouchError::ResponseMismatch => write!(f, "The response did not match the request"),
+            MaxTouchError::ShortRead => write!(f, "Device returned less data than expected"),
+            MaxTouchError::ObjectNotFound(id) => write!(f, "Object {} not found", id),
+            MaxTouchError::UnsupportedObject(id) => write!(f, "Object type {} is not serializable", id),
+            MaxTouchError::UnsupportedCommand(command) => write!(f, "The firmware does not support {}", command),
+            MaxTouchError::OutOfBounds => write!(f, "Access is outside of the object"),
+            MaxTouchError::VersionMismatch { expected, got } => {
+                write!(f, "Protocol version mismatch, expected {} got {}", expected, got)
+            }
+            MaxTouchError::Hid(e) => write!(f, "HID error: {}", e),
+            MaxTouchError::Encoding(e) => write!(f, "Encoding error: {}", e),
+            MaxTouchError::Io(e) => write!(f, "IO error: {}", e),
+            MaxTouchError::ReplayMismatch(e) => write!(f, "Replay does not match the capture: {}", e),
+            MaxTouchError::ConfirmationRequired => write!(f, "The confirmation token is missing, wrong or has expired"),
+            MaxTouchError::CommandFailed(command) => write!(f, "The device reported that {} failed", command),
+            MaxTouchError::ConfigMismatch(e) => write!(f, "The configuration does not fit this device: {}", e),
+            MaxTouchError::NoConfigObjects => write!(f, "The device has neither T7 nor T71, so there is no configuration checksum"),
+        }
+    }
+}
+
+impl std::error::Error for MaxTouchError {}
+
+impl From<hidapi::HidError> for MaxTouchError {
+    fn from(e: hidapi::HidError) -> Self {
+        MaxTouchError::Hid(e.to_string())
+    }
+}
+
+impl From<serde_json::Error> for MaxTouchError {
+    fn from(e: serde_json::Error) -> Self {
+        MaxTouchError::Encoding(e.to_string())
+    }
+}
+
+impl From<image::ImageError> for MaxTouchError {
+    fn from(e: image::ImageError) -> Self {
+        MaxTouchError::Encoding(e.to_string())
+    }
+}
+
+impl From<std::io::Error> for MaxTouchError {
+    fn from(e: std::io::Error) -> Self {
+        MaxTouchError::Io(e.to_string())
+    }
+}
@@ -0,0 +1,126 @@
+use crate::error::MaxTouchError;
+use crate::maxtouch::T6CommandProcessor;
+use crate::session::{SessionInfo, SessionManager};
+use crate::transport::{HidTransport, Transport};
+use crate::worker::Priority;
+use crate::{initialize_device, write_object_impl, ConnectionState};
+use hidapi::HidApi;
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::thread;
+use std::time::Duration;
+use tauri::{AppHandle, Emitter, Manager, Runtime};
+use zerocopy::{AsBytes, FromZeroes};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(1000);
+
+#[derive(Serialize, Clone)]
+struct DeviceDisconnected {
+    session: String,
+}
+
+/// Start a background thread which watches for the raw HID interfaces of
+/// connected sessions disappearing and reappearing. Sessions survive the
+/// device going away, when it comes back (possibly on a new path after being
+/// reflashed) the device is reinitialized and the last debug mode restored.
+pub fn spawn<R: Runtime>(app: AppHandle<R>) {
+    thread::spawn(move || {
+        let mut api = match HidApi::new() {
+            Ok(api) => api,
+            Err(e) => {
+                println!("Hotplug detection disabled: {}", e);
+                return;
+            }
+        };
+        loop {
+            thread::sleep(POLL_INTERVAL);
+            match api.refresh_devices() {
+                Ok(_) => poll(&app, &api),
+                Err(e) => println!("Failed to refresh the device list: {}", e),
+            }
+        }
+    });
+}
+
+fn poll<R: Runtime>(app: &AppHandle<R>, api: &HidApi) {
+    let workers = app.state::<Mutex<SessionManager>>().lock().workers();
+    let mut states = Vec::new();
+    for (session, worker) in workers {
+        let state = worker.call_blocking(Priority::High, |connection| {
+            Ok((connection.identity.clone(), connection.path.clone(), connection.device.is_some()))
+        });
+        // Only hardware sessions can be replugged.
+        if let Ok((Some(identity), path, connected)) = state {
+            states.push((session, worker, identity, path, connected));
+        }
+    }
+    let mut claimed: Vec<String> = states.iter()
+        .filter(|(_, _, _, _, connected)| *connected)
+        .filter_map(|(_, _, _, path, _)| path.clone())
+        .collect();
+
+    for (session, worker, identity, path, connected) in states {
+        if connected {
+            let present = match &path {
+                Some(path) => api.device_list().any(|device| device.path().to_string_lossy() == path.as_str()),
+                None => false,
+            };
+            if !present {
+                println!("Device disconnected: {}", session);
+                let _ = worker.call_blocking(Priority::High, |connection| {
+                    connection.device = None;
+                    Ok(())
+                });
+                let _ = app.emit("device-disconnected", DeviceDisconnected { session });
+            }
+            continue;
+        }
+
+        let device = api.device_list().find(|device| {
+            identity.matches(device) && !claimed.contains(&device.path().to_string_lossy().into_owned())
+        });
+        if let Some(device) = device {
+            let path = device.path().to_string_lossy().into_owned();
+            let transport = match device.open_device(api) {
+                Ok(device) => HidTransport::new(device),
+                Err(e) => {
+                    println!("Failed to reopen {}: {}", session, e);
+                    continue;
+                }
+            };
+            let reconnect_session = session.clone();
+            let reconnect_path = path.clone();
+            let result = worker.call_blocking(Priority::High, move |connection| {
+                reconnect(connection, Box::new(transport), reconnect_path, &reconnect_session)
+            });
+            match result {
+                Ok(info) => {
+                    println!("Device reconnected: {}", session);
+                    claimed.push(path);
+                    let _ = app.emit("device-connected", info);
+                }
+                Err(e) => {
+                    // The device may still be enumerating, try again on the next poll.
+                    println!("Failed to reconnect {}: {}", session, e);
+                }
+            }
+        }
+    }
+}
+
+fn reconnect(connection: &mut ConnectionState, device: Box<dyn Transport>, path: String, session: &str) -> Result<SessionInfo, MaxTouchError> {
+    connection.device = Some(device);
+    connection.path = Some(path);
+    let result = initialize_device(connection).and_then(|_| {
+        if let Some(mode) = connection.debug_mode {
+            let mut t6: T6CommandProcessor = FromZeroes::new_zeroed();
+            t6.diagnostic = mode;
+            write_object_impl(connection, 6, 0, t6.as_bytes())?;
+        }
+        connection.session_info(session).ok_or(MaxTouchError::NotConnected)
+    });
+    if result.is_err() {
+        connection.device = None;
+    }
+    result
+}
@@ -0,0 +1,173 @@
+use crate::capture::hex_bytes;
+use crate::error::MaxTouchError;
+use crate::layout;
+use serde::Serialize;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::time::SystemTime;
+
+/// One register write, with what the registers held before it.
+#[derive(Serialize, Debug, Clone)]
+pub struct JournalEntry {
+    pub object_type: u8,
+    pub instance: u16,
+    pub offset: u16,
+    /// Name of the field the write starts in, when the object is known.
+    pub field: Option<String>,
+    #[serde(with = "hex_bytes")]
+    pub old: Vec<u8>,
+    #[serde(with = "hex_bytes")]
+    pub new: Vec<u8>,
+    /// Milliseconds since the Unix epoch.
+    pub timestamp_ms: u64,
+}
+
+impl JournalEntry {
+    pub fn new(object_type: u8, instance: u16, offset: u16, old: Vec<u8>, new: Vec<u8>) -> Self {
+        let field = layout::object_layout(object_type).and_then(|fields| {
+            fields.into_iter()
+                .find(|field| (field.offset..field.offset + field.width as u16).contains(&offset))
+                .map(|field| field.name)
+        });
+        JournalEntry {
+            object_type,
+            instance,
+            offset,
+            field,
+            old,
+            new,
+            timestamp_ms: SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_millis() as u64,
+        }
+    }
+}
+
+/// The register writes made in a session. Entries before `position` are
+/// applied, the rest have been undone and can be redone until the next write.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct Journal {
+    entries: Vec<JournalEntry>,
+    position: usize,
+}
+
+impl Journal {
+    /// Record a write, dropping anything which was undone.
+    pub fn record(&mut self, entry: JournalEntry) {
+        self.entries.truncate(self.position);
+        self.entries.push(entry);
+        self.position += 1;
+    }
+
+    /// The write `undo` would revert. The journal only moves once the old
+    /// bytes are back on the device, see `mark_undone`.
+    pub fn undo_entry(&self) -> Option<&JournalEntry> {
+        self.entries[..self.position].last()
+    }
+
+    pub fn redo_entry(&self) -> Option<&JournalEntry> {
+        self.entries.get(self.position)
+    }
+
+    /// Forget every entry, for when the device no longer holds what they recorded.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.position = 0;
+    }
+
+    pub fn mark_undone(&mut self) {
+        self.position = self.position.saturating_sub(1);
+    }
+
+    pub fn mark_redone(&mut self) {
+        self.position = (self.position + 1).min(self.entries.len());
+    }
+
+    /// Write the applied entries as JSON lines, oldest first.
+    pub fn export(&self, path: &Path) -> Result<usize, MaxTouchError> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        for entry in &self.entries[..self.position] {
+            serde_json::to_writer(&mut writer, entry)?;
+            writer.write_all(b"\n")?;
+        }
+        writer.flush()?;
+        Ok(self.position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(value: u8) -> JournalEntry {
+        JournalEntry::new(7, 0, 0, vec![value - 1], vec![value])
+    }
+
+    fn undo_value(journal: &Journal) -> Option<u8> {
+        journal.undo_entry().map(|entry| entry.new[0])
+    }
+
+    fn redo_value(journal: &Journal) -> Option<u8> {
+        journal.redo_entry().map(|entry| entry.new[0])
+    }
+
+    #[test]
+    fn entries_name_the_field() {
+        assert_eq!(JournalEntry::new(7, 0, 1, vec![0], vec![1]).field.as_deref(), Some("actacqint"));
+        // Objects without a struct have no field names.
+        assert_eq!(JournalEntry::new(38, 0, 1, vec![0], vec![1]).field, None);
+    }
+
+    #[test]
+    fn undo_and_redo() {
+        let mut journal = Journal::default();
+        assert_eq!(undo_value(&journal), None);
+        journal.record(entry(1));
+        journal.record(entry(2));
+        assert_eq!((undo_value(&journal), redo_value(&journal)), (Some(2), None));
+
+        journal.mark_undone();
+        assert_eq!((undo_value(&journal), redo_value(&journal)), (Some(1), Some(2)));
+        journal.mark_undone();
+        journal.mark_undone();
+        assert_eq!((undo_value(&journal), redo_value(&journal)), (None, Some(1)));
+
+        journal.mark_redone();
+        journal.mark_redone();
+        journal.mark_redone();
+        assert_eq!((undo_value(&journal), redo_value(&journal)), (Some(2), None));
+    }
+
+    #[test]
+    fn a_write_after_undo_drops_the_undone_writes() {
+        let mut journal = Journal::default();
+        journal.record(entry(1));
+        journal.record(entry(2));
+        journal.mark_undone();
+        journal.record(entry(3));
+        assert_eq!((undo_value(&journal), redo_value(&journal)), (Some(3), None));
+        journal.mark_undone();
+        assert_eq!(undo_value(&journal), Some(1));
+
+        journal.clear();
+        assert_eq!((undo_value(&journal), redo_value(&journal)), (None, None));
+    }
+
+    #[test]
+    fn export_writes_the_applied_entries() {
+        let mut journal = Journal::default();
+        journal.record(entry(1));
+        journal.record(entry(2));
+        journal.record(entry(3));
+        journal.mark_undone();
+
+        let path = std::env::temp_dir().join(format!("maxtouch-journal-{}.jsonl", std::process::id()));
+        assert_eq!(journal.export(&path).unwrap(), 2);
+        let text = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let lines: Vec<serde_json::Value> = text.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[1]["old"], "01");
+        assert_eq!(lines[1]["new"], "02");
+        assert_eq!(lines[1]["field"], "idleacqint");
+    }
+}
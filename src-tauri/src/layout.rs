@@ -0,0 +1,176 @@
+use crate::error::MaxTouchError;
+use crate::{decode_object, DecodedObject};
+use serde::ser::{self, Impossible, SerializeStruct, SerializeTuple, Serializer};
+use serde::Serialize;
+use std::fmt::Display;
+
+/// Where a named register sits within an object.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct FieldLayout {
+    pub name: String,
+    pub offset: u16,
+    /// Width in bytes, multi byte fields are little endian.
+    pub width: u8,
+}
+
+impl FieldLayout {
+    /// Read the field from the contents of an object instance.
+    pub fn value(&self, data: &[u8]) -> Option<u32> {
+        let start = self.offset as usize;
+        let bytes = data.get(start..start + self.width as usize)?;
+        Some(bytes.iter().rev().fold(0, |value, byte| (value << 8) | *byte as u32))
+    }
+}
+
+impl ser::Error for MaxTouchError {
+    fn custom<T: Display>(msg: T) -> Self {
+        MaxTouchError::Encoding(msg.to_string())
+    }
+}
+
+/// The register layout of an object, for the objects described in `maxtouch`.
+/// Arrays are split into one field per element, named `name[index]`.
+pub fn object_layout(object_type: u8) -> Option<Vec<FieldLayout>> {
+    // Decoding only needs enough data, the layout does not depend on the values.
+    let decoded = decode_object(object_type, &[0; 256]).ok()?;
+    layout(&decoded).ok()
+}
+
+/// Walk the serialized form of a decoded object, recording the name and
+/// width of every field. This relies on the structs being packed with fields
+/// in register order, which they must be to decode at all.
+pub fn layout(decoded: &DecodedObject) -> Result<Vec<FieldLayout>, MaxTouchError> {
+    let mut layout = Layout { fields: Vec::new(), offset: 0, name: String::new() };
+    decoded.serialize(&mut layout)?;
+    Ok(layout.fields)
+}
+
+struct Layout {
+    fields: Vec<FieldLayout>,
+    offset: u16,
+    name: String,
+}
+
+impl Layout {
+    fn push(&mut self, width: u8) -> Result<(), MaxTouchError> {
+        self.fields.push(FieldLayout { name: self.name.clone(), offset: self.offset, width });
+        self.offset += width as u16;
+        Ok(())
+    }
+}
+
+fn unsupported(kind: &str) -> MaxTouchError {
+    MaxTouchError::Encoding(format!("Registers cannot be laid out as {}", kind))
+}
+
+impl<'a> Serializer for &'a mut Layout {
+    type Ok = ();
+    type Error = MaxTouchError;
+    type SerializeSeq = Impossible<(), MaxTouchError>;
+    type SerializeTuple = ElementLayout<'a>;
+    type SerializeTupleStruct = Impossible<(), MaxTouchError>;
+    type SerializeTupleVariant = Impossible<(), MaxTouchError>;
+    type SerializeMap = Impossible<(), MaxTouchError>;
+    type SerializeStruct = Self;
+    type SerializeStructVariant = Impossible<(), MaxTouchError>;
+
+    fn serialize_u8(self, _: u8) -> Result<(), MaxTouchError> { self.push(1) }
+    fn serialize_i8(self, _: i8) -> Result<(), MaxTouchError> { self.push(1) }
+    fn serialize_u16(self, _: u16) -> Result<(), MaxTouchError> { self.push(2) }
+    fn serialize_i16(self, _: i16) -> Result<(), MaxTouchError> { self.push(2) }
+    fn serialize_u32(self, _: u32) -> Result<(), MaxTouchError> { self.push(4) }
+    fn serialize_i32(self, _: i32) -> Result<(), MaxTouchError> { self.push(4) }
+
+    fn serialize_bool(self, _: bool) -> Result<(), MaxTouchError> { Err(unsupported("bool")) }
+    fn serialize_i64(self, _: i64) -> Result<(), MaxTouchError> { Err(unsupported("i64")) }
+    fn serialize_u64(self, _: u64) -> Result<(), MaxTouchError> { Err(unsupported("u64")) }
+    fn serialize_f32(self, _: f32) -> Result<(), MaxTouchError> { Err(unsupported("f32")) }
+    fn serialize_f64(self, _: f64) -> Result<(), MaxTouchError> { Err(unsupported("f64")) }
+    fn serialize_char(self, _: char) -> Result<(), MaxTouchError> { Err(unsupported("char")) }
+    fn serialize_str(self, _: &str) -> Result<(), MaxTouchError> { Err(unsupported("str")) }
+    fn serialize_bytes(self, _: &[u8]) -> Result<(), MaxTouchError> { Err(unsupported("bytes")) }
+    fn serialize_none(self) -> Result<(), MaxTouchError> { Err(unsupported("option")) }
+    fn serialize_some<T: ?Sized + Serialize>(self, _: &T) -> Result<(), MaxTouchError> { Err(unsupported("option")) }
+    fn serialize_unit(self) -> Result<(), MaxTouchError> { Err(unsupported("unit")) }
+    fn serialize_unit_struct(self, _: &'static str) -> Result<(), MaxTouchError> { Err(unsupported("unit")) }
+
+    fn serialize_unit_variant(self, _: &'static str, _: u32, _: &'static str) -> Result<(), MaxTouchError> {
+        Err(unsupported("enum"))
+    }
+
+    fn serialize_newtype_struct<T: ?Sized + Serialize>(self, _: &'static str, value: &T) -> Result<(), MaxTouchError> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: ?Sized + Serialize>(self, _: &'static str, _: u32, _: &'static str, _: &T)
+        -> Result<(), MaxTouchError> {
+        Err(unsupported("enum"))
+    }
+
+    fn serialize_seq(self, _: Option<usize>) -> Result<Self::SerializeSeq, MaxTouchError> {
+        Err(unsupported("variable length sequence"))
+    }
+
+    fn serialize_tuple(self, _: usize) -> Result<Self::SerializeTuple, MaxTouchError> {
+        let base = self.name.clone();
+        Ok(ElementLayout { layout: self, base, index: 0 })
+    }
+
+    fn serialize_tuple_struct(self, _: &'static str, _: usize) -> Result<Self::SerializeTupleStruct, MaxTouchError> {
+        Err(unsupported("tuple struct"))
+    }
+
+    fn serialize_tuple_variant(self, _: &'static str, _: u32, _: &'static str, _: usize)
+        -> Result<Self::SerializeTupleVariant, MaxTouchError> {
+        Err(unsupported("enum"))
+    }
+
+    fn serialize_map(self, _: Option<usize>) -> Result<Self::SerializeMap, MaxTouchError> {
+        Err(unsupported("map"))
+    }
+
+    fn serialize_struct(self, _: &'static str, _: usize) -> Result<Self::SerializeStruct, MaxTouchError> {
+        Ok(self)
+    }
+
+    fn serialize_struct_variant(self, _: &'static str, _: u32, _: &'static str, _: usize)
+        -> Result<Self::SerializeStructVariant, MaxTouchError> {
+        Err(unsupported("enum"))
+    }
+}
+
+impl SerializeStruct for &mut Layout {
+    type Ok = ();
+    type Error = MaxTouchError;
+
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, key: &'static str, value: &T) -> Result<(), MaxTouchError> {
+        self.name = key.to_string();
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), MaxTouchError> {
+        Ok(())
+    }
+}
+
+/// Lays out the elements of a fixed size array.
+struct ElementLayout<'a> {
+    layout: &'a mut Layout,
+    base: String,
+    index: usize,
+}
+
+impl SerializeTuple for ElementLayout<'_> {
+    type Ok = ();
+    type Error = MaxTouchError;
+
+    fn serialize_element<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), MaxTouchError> {
+        self.layout.name = format!("{}[{}]", self.base, self.index);
+        self.index += 1;
+        value.serialize(&mut *self.layout)
+    }
+
+    fn end(self) -> Result<(), MaxTouchError> {
+        Ok(())
+    }
+}
@@ -1,30 +1,82 @@
 extern crate hidapi;
-use hidapi::{HidApi, HidDevice};
+use hidapi::{DeviceInfo, HidApi};
 use maxtouch::{InformationBlock, ObjectTableElement, T6CommandProcessor,
     T7PowerConfig, T8AcquisitionConfig, T25SelfTest, T42TouchSupression, T46CteConfig,
     T47ProciStylus, T56Shieldless, T65LensBending, T80RetransmissionCompensation,
     T100MultipleTouchTouchscreen};
 use parking_lot::Mutex;
 use std::{cmp, mem};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 use std::default::Default;
-use tauri::State;
+use tauri::{AppHandle, Emitter, Manager, State};
 use zerocopy::{FromBytes, FromZeroes, AsBytes};
 use tauri::ipc::Response;
 use image::{codecs::png::PngEncoder, Rgb, RgbImage, ImageEncoder};
+use batch::{ObjectDump, ReadRange};
+use capture::{CaptureHeader, CaptureWriter, Direction, ReplayTransport};
+use checksum::{ConfigChecksum, ObjectImage, NON_CONFIG_OBJECTS};
+use config_file::{ConfigFile, ConfigSource, LoadReport};
 use serde::{Deserialize, Serialize};
+use devices::{DeviceDescriptor, DeviceFilter, DeviceMatcher};
+use diff::FieldChange;
+use error::MaxTouchError;
+use journal::{Journal, JournalEntry};
+use protocol::{ProtocolConfig, ProtocolInfo};
+use messages::{CommandProcessorStatus, T6_BACKUP_NVM, T6_CALIBRATE, T6_REPORT_ALL, T6_RESET};
+use protocol_log::ProtocolLogger;
+use qmk::CodeStyle;
+use report_ids::{ReportIdMap, ReportSource};
+use session::{SessionInfo, SessionManager, SIMULATOR_SESSION};
+use simulator::Simulator;
+use touch::{TouchConfig, TouchFrame, TouchReport};
+use transport::{HidTransport, Transport};
+use worker::{DeviceWorker, Priority};
 
+pub mod batch;
+pub mod capture;
+pub mod checksum;
+pub mod config_file;
+pub mod devices;
+pub mod diff;
+pub mod error;
+mod hotplug;
+pub mod journal;
+pub mod layout;
 mod maxtouch;
+pub mod messages;
+pub mod obp_raw;
+pub mod protocol;
+pub mod protocol_log;
+pub mod qmk;
+pub mod report_ids;
+pub mod session;
+pub mod simulator;
+pub mod touch;
+pub mod transport;
+mod worker;
+pub mod xcfg;
 
-const VENDOR_ID: u16 = 0xFEED;
-const PRODUCT_ID: u16 = 0x0000;
-const USAGE_PAGE: u16 = 0xFF60;
-const USAGE: u16 = 0x61;
 const REPORT_LENGTH: usize = 32 + 1;
+/// Upper bound on stale reports discarded before a request, in case the
+/// device is streaming.
+const MAX_DRAIN_REPORTS: usize = 64;
+/// Registers are addressed with 16 bits.
+const MEMORY_SIZE: usize = 0x10000;
+/// The information block and object table are followed by a 24 bit checksum.
+const INFO_CRC_SIZE: usize = 3;
+
+/// How long a backup confirmation token stays valid.
+const BACKUP_TOKEN_LIFETIME: Duration = Duration::from_secs(30);
 
 #[repr(u8)]
 enum MaxTouchStatus {
     OK = 0,
+    Error = 0xFF,
 }
 
 #[repr(u8)]
@@ -45,223 +97,746 @@ enum MaxTouchCommandType {
 #[derive(Debug)]
 pub struct ObjectDetails {
     address: u16,
-    size: u8,
-    instances: u8,
+    size: u16,
+    instances: u16,
+    report_ids: u8,
+}
+
+impl ObjectDetails {
+    /// Bytes used by all instances of the object.
+    fn length(&self) -> usize {
+        self.size as usize * self.instances as usize
+    }
+
+    /// Address of one instance of the object.
+    fn instance_address(&self, instance: u16) -> Result<u16, MaxTouchError> {
+        if instance >= self.instances {
+            return Err(MaxTouchError::OutOfBounds);
+        }
+        let address = self.address as usize + instance as usize * self.size as usize;
+        u16::try_from(address).map_err(|_| MaxTouchError::OutOfBounds)
+    }
+}
+
+/// An object table entry as reported to the frontend.
+#[derive(Serialize, Debug, Clone)]
+pub struct ObjectInfo {
+    object_type: u8,
+    address: u16,
+    size: u16,
+    instances: u16,
+    report_ids_per_instance: u8,
 }
 
 #[derive(Default)]
 pub struct ConnectionState {
-    device: Option<HidDevice>,
+    device: Option<Box<dyn Transport>>,
+    path: Option<String>,
+    identity: Option<DeviceMatcher>,
+    debug_mode: Option<u8>,
+    protocol: ProtocolInfo,
+    protocol_config: ProtocolConfig,
+    info: Option<InformationBlock>,
     sensor_size: [u8; 2],
     invert_x: bool,
     invert_y: bool,
     switch_xy: bool,
     object_table: HashMap<u8, ObjectDetails>,
+    /// The object table in the order the chip reports it.
+    elements: Vec<ObjectTableElement>,
+    report_ids: ReportIdMap,
+    poll_messages: bool,
+    touch_config: TouchConfig,
+    /// Touches which are down, keyed by touch ID.
+    touches: BTreeMap<u8, TouchReport>,
+    backup_token: Option<(String, Instant)>,
+    /// Register writes made through `write_register`, for undo and redo.
+    journal: Journal,
+    capture: Option<CaptureWriter>,
+    log: Option<ProtocolLogger>,
 }
 
-fn check_version(connection: &ConnectionState) -> Result<(), String> {
-    match &connection.device {
-        Some(device) => {
-            let mut data: [u8; REPORT_LENGTH] = [0; REPORT_LENGTH];
-            data[0] = 0x0; // First byte of the first message is the Report ID
-            data[1] = MaxTouchCommand::CheckVersion as u8; // Command
-            data[2] = 0x9A; // Magic
-            data[3] = 0x4D; // Magic
-            data[4] = 0x00; // Version
-            data[5] = 0x01; // Version
-
-            match device.write(&data) {
-                Ok(_) => {},
-                Err(_) => { return Err(format!("Failed to write to the device.")); }
+impl ConnectionState {
+    pub fn new(device: Box<dyn Transport>) -> Self {
+        ConnectionState {
+            device: Some(device),
+            ..Default::default()
+        }
+    }
+
+    fn require(&self, capability: u32, command: &str) -> Result<(), MaxTouchError> {
+        if self.protocol.supports(capability) {
+            return Ok(());
+        }
+        Err(MaxTouchError::UnsupportedCommand(command.to_string()))
+    }
+
+    /// Send a report to the device, recording it if a capture or the
+    /// protocol log is running.
+    fn write_report(&self, data: &[u8]) -> Result<usize, MaxTouchError> {
+        let device = self.device.as_ref().ok_or(MaxTouchError::NotConnected)?;
+        self.trace(Direction::Out, data);
+        device.write(data)
+    }
+
+    /// Read a report from the device, a size of zero means the read timed out.
+    fn read_report(&self, data: &mut [u8], timeout: i32) -> Result<usize, MaxTouchError> {
+        let device = self.device.as_ref().ok_or(MaxTouchError::NotConnected)?;
+        let size = device.read_timeout(data, timeout)?;
+        if size > 0 {
+            self.trace(Direction::In, &data[..size]);
+        }
+        Ok(size)
+    }
+
+    /// Discard any reports left over from earlier requests, such as a
+    /// response which arrived after its request timed out.
+    fn drain_input(&self) -> Result<usize, MaxTouchError> {
+        let mut data: [u8; REPORT_LENGTH] = [0; REPORT_LENGTH];
+        let mut drained = 0;
+        while drained < MAX_DRAIN_REPORTS && self.read_report(&mut data, 0)? > 0 {
+            drained += 1;
+        }
+        Ok(drained)
+    }
+
+    /// Whether the device acts on a request as it receives it, so that sending
+    /// it again after a lost response would repeat the effect. Writes to T6
+    /// reset, back up or calibrate, and reading T5 acknowledges the message.
+    fn has_side_effects(&self, request: &[u8]) -> bool {
+        let object = match request.get(1) {
+            Some(command) if *command == MaxTouchCommand::Write as u8 => 6,
+            Some(command) if *command == MaxTouchCommand::Read as u8 => 5,
+            _ => return false,
+        };
+        let (Some(object), Some(header)) = (self.object_table.get(&object), request.get(2..5)) else {
+            return false;
+        };
+        let start = u16::from_le_bytes([header[0], header[1]]) as usize;
+        let end = start + header[2] as usize;
+        start < object.address as usize + object.length() && end > object.address as usize
+    }
+
+    /// Send a request and wait for its response. Requests which time out, or
+    /// are answered with a response to some other request, are resent with
+    /// an increasing delay until the retries run out. Requests with side
+    /// effects are only sent once.
+    fn transaction(&self, request: &[u8]) -> Result<([u8; REPORT_LENGTH], usize), MaxTouchError> {
+        self.counted_transaction(request, &mut 0)
+    }
+
+    /// `transaction`, adding the number of times the request was sent to `sent`.
+    fn counted_transaction(&self, request: &[u8], sent: &mut usize) -> Result<([u8; REPORT_LENGTH], usize), MaxTouchError> {
+        let config = self.protocol_config;
+        let retries = match self.has_side_effects(request) {
+            true => 0,
+            false => config.retries,
+        };
+        let mut backoff = Duration::from_millis(config.backoff_ms);
+        let mut error = MaxTouchError::Timeout;
+        for attempt in 0..=retries {
+            if attempt > 0 {
+                thread::sleep(backoff);
+                backoff *= 2;
             }
-            match device.read_timeout(&mut data, 1000) {
-                Ok(size) => {
-                    if size > 0 && data[0] == MaxTouchStatus::OK as u8 {
-                        return Ok(());
-                    }
-                    return Err(format!("Version check failure. Error {}", data[0]));
-                }
-                Err(e) => {
-                    return Err(format!("Read returned error {}", e));
-                }
+            if config.drain {
+                self.drain_input()?;
+            }
+            self.write_report(request)?;
+            *sent += 1;
+            let mut response: [u8; REPORT_LENGTH] = [0; REPORT_LENGTH];
+            let size = self.read_report(&mut response, config.timeout_ms)?;
+            if size == 0 {
+                error = MaxTouchError::Timeout;
+            }
+            else if !protocol::response_matches(request, &response[..size]) {
+                error = MaxTouchError::ResponseMismatch;
             }
+            else {
+                return Ok((response, size));
+            }
+        }
+        Err(error)
+    }
+
+    fn trace(&self, direction: Direction, data: &[u8]) {
+        if let Some(capture) = &self.capture {
+            capture.record(direction, data);
         }
-        _ => {
-            return Err(format!("Not connected."));
+        if let Some(log) = &self.log {
+            log.log(direction, data, &self.object_table);
         }
     }
-}
 
-fn read_data(connection: &ConnectionState, address: u16, length: u8) -> Result<Vec<u8>, String> {
-    match &connection.device {
-        Some(device) => {
-            let mut response = Vec::new();
-            let mut data: [u8; REPORT_LENGTH] = [0; REPORT_LENGTH];
-            let mut remaining = length as usize;
-            for offset in (0..length).step_by(REPORT_LENGTH - 5) {
-                let read_length = cmp::min(remaining, REPORT_LENGTH - 5);
-                data[0] = 0x0; // First byte of the first message is the Report ID
-                data[1] = MaxTouchCommand::Read as u8;              // Command
-                data[2] = ((address + offset as u16) & 0xff) as u8; // Address Low
-                data[3] = ((address + offset as u16) >> 8) as u8;   // Address High
-                data[4] = read_length as u8;                        // Length
-
-                match device.write(&data) {
-                    Ok(_) => {}
-                    Err(e) => {
-                        return Err(format!("Write returned error {}", e));
-                    }
-                }
-                match device.read_timeout(&mut data, 1000) {
-                    Ok(size) => {
-                        if size > 0 && data[0] != MaxTouchStatus::OK as u8 {
-                            return Err(format!("Device reported an error ({})", data[0]));
-                        }
-
-                        response.extend_from_slice(&data[4..(4 + read_length)]);
-                        remaining -= read_length;
-                    }
-                    Err(e) => {
-                        return Err(format!("Read returned error {}", e));
-                    }
-                }
-            }
-            return Ok(response);
+    /// Regions of memory which are known to exist: the information block and
+    /// object table, and every instance of every object.
+    fn memory_map(&self) -> Result<Vec<ReadRange>, MaxTouchError> {
+        let num_objects = self.info.as_ref().map_or(self.object_table.len(), |info| info.num_objects as usize);
+        let table_length = mem::size_of::<InformationBlock>()
+            + mem::size_of::<ObjectTableElement>() * num_objects
+            + INFO_CRC_SIZE;
+        let mut regions = vec![ReadRange { address: 0, length: cmp::min(table_length, u16::MAX as usize) as u16 }];
+        regions.extend(self.object_table.values().map(|object| ReadRange {
+            address: object.address,
+            length: cmp::min(object.length(), u16::MAX as usize) as u16,
+        }));
+        batch::merge_ranges(&regions)
+    }
+
+    /// Check that an access stays inside the 16 bit address space and, once
+    /// the object table has been read, inside memory the table describes.
+    fn check_access(&self, address: u16, length: usize) -> Result<(), MaxTouchError> {
+        if address as usize + length > MEMORY_SIZE {
+            return Err(MaxTouchError::OutOfBounds);
         }
-        _ => return Err(format!("Not connected.")),
-    }
-}
-
-fn write_data(connection: &ConnectionState, address: u16, data: &[u8]) -> Result<(), String> {
-    match &connection.device {
-        Some(device) => {
-            let mut packet: [u8; REPORT_LENGTH] = [0; REPORT_LENGTH];
-            let mut remaining = data.len();
-            for offset in (0..data.len()).step_by(REPORT_LENGTH - 5) {
-                let write_length = cmp::min(remaining, REPORT_LENGTH - 5);
-                packet[0] = 0x0; // First byte of the first message is the Report ID
-                packet[1] = MaxTouchCommand::Write as u8;               // Command
-                packet[2] = ((address + offset as u16) & 0xff) as u8;   // Address Low
-                packet[3] = ((address + offset as u16) >> 8) as u8;     // Address High
-                packet[4] = write_length as u8;                         // Length
-                packet[5..(5 + write_length)].clone_from_slice(&data[offset..(offset + write_length)]);
-
-                match device.write(&packet) {
-                    Ok(_) => {},
-                    Err(_) => { return Err(format!("Failed to write to the device.")); }
-                }
-                match device.read_timeout(&mut packet, 1000) {
-                    Ok(size) => {
-                        if size > 0 && packet[0] != MaxTouchStatus::OK as u8 {
-                            return Err(format!("Device reported an error ({})", packet[0]));
-                        }
-                        remaining -= write_length;
-                    }
-                    Err(e) => {
-                        return Err(format!("Read returned error {}", e));
-                    }
-                }
-            }
+        if self.object_table.is_empty() || length == 0 {
             return Ok(());
         }
-        _ => return Err(format!("Not connected.")),
+        let range = ReadRange {
+            address,
+            length: u16::try_from(length).map_err(|_| MaxTouchError::OutOfBounds)?,
+        };
+        match self.memory_map()?.iter().any(|region| region.contains(&range)) {
+            true => Ok(()),
+            false => Err(MaxTouchError::OutOfBounds),
+        }
+    }
+
+    /// What a replay of a capture started now needs to know about the session.
+    fn capture_header(&self) -> Option<CaptureHeader> {
+        Some(CaptureHeader {
+            protocol: self.protocol,
+            info: self.info.clone()?,
+            objects: self.elements.clone(),
+            invert_x: self.invert_x,
+            invert_y: self.invert_y,
+            switch_xy: self.switch_xy,
+            touch_config: self.touch_config,
+        })
+    }
+
+    /// Pick up a replayed session where the capture started.
+    fn restore_capture_header(&mut self, header: CaptureHeader) {
+        self.protocol = header.protocol;
+        apply_object_table(self, header.info, header.objects);
+        self.invert_x = header.invert_x;
+        self.invert_y = header.invert_y;
+        self.switch_xy = header.switch_xy;
+        self.touch_config = header.touch_config;
+    }
+
+    fn session_info(&self, session: &str) -> Option<SessionInfo> {
+        Some(SessionInfo {
+            session: session.to_string(),
+            connected: self.device.is_some(),
+            protocol: self.protocol,
+            capabilities: self.protocol.capability_names(),
+            info: self.info.clone()?,
+            sensor_size: self.sensor_size,
+            invert_x: self.invert_x,
+            invert_y: self.invert_y,
+            switch_xy: self.switch_xy,
+        })
+    }
+}
+
+fn check_version(connection: &ConnectionState) -> Result<ProtocolInfo, MaxTouchError> {
+    let mut data: [u8; REPORT_LENGTH] = [0; REPORT_LENGTH];
+    data[0] = 0x0; // First byte of the first message is the Report ID
+    data[1] = MaxTouchCommand::CheckVersion as u8; // Command
+    data[2..4].copy_from_slice(&protocol::MAGIC.to_be_bytes());               // Magic
+    data[4..6].copy_from_slice(&protocol::PROTOCOL_VERSION.to_be_bytes());    // Version
+
+    let (data, _) = connection.transaction(&data)?;
+    if data[0] != MaxTouchStatus::OK as u8 {
+        return Err(MaxTouchError::DeviceStatus(data[0]));
+    }
+    let protocol = ProtocolInfo::from_response(&data);
+    if protocol.version < protocol::PROTOCOL_VERSION {
+        return Err(MaxTouchError::VersionMismatch { expected: protocol::PROTOCOL_VERSION, got: protocol.version });
     }
+    Ok(protocol)
+}
+
+/// Build a request to read up to one report's worth of data.
+fn read_request(address: u16, length: u8) -> [u8; REPORT_LENGTH] {
+    let mut data: [u8; REPORT_LENGTH] = [0; REPORT_LENGTH];
+    data[0] = 0x0; // First byte of the first message is the Report ID
+    data[1] = MaxTouchCommand::Read as u8;  // Command
+    data[2] = (address & 0xff) as u8;       // Address Low
+    data[3] = (address >> 8) as u8;         // Address High
+    data[4] = length;                       // Length
+    data
 }
 
-fn read_object_impl(connection: &ConnectionState, id: u8) -> Result<Vec<u8>, String> {
-    if connection.object_table.contains_key(&id) {
-        return read_data(connection, connection.object_table[&id].address, connection.object_table[&id].size);
+/// Check the status of a read response and return the data it carries.
+fn read_payload(reply: &[u8], size: usize, length: usize) -> Result<&[u8], MaxTouchError> {
+    if reply[0] != MaxTouchStatus::OK as u8 {
+        return Err(MaxTouchError::DeviceStatus(reply[0]));
     }
-    Err(format!("Object {} not found", id))
+    if size < 4 + length {
+        return Err(MaxTouchError::ShortRead);
+    }
+    Ok(&reply[4..(4 + length)])
 }
 
-fn write_object_impl(connection: &ConnectionState, id: u8, data: &[u8]) -> Result<(), String> {
-    if connection.object_table.contains_key(&id) {
-        return write_data(connection, connection.object_table[&id].address, data);
+fn read_data(connection: &ConnectionState, address: u16, length: u16) -> Result<Vec<u8>, MaxTouchError> {
+    connection.check_access(address, length as usize)?;
+    let mut response = Vec::new();
+    let mut remaining = length as usize;
+    for offset in (0..length as usize).step_by(REPORT_LENGTH - 5) {
+        let read_length = cmp::min(remaining, REPORT_LENGTH - 5);
+        let data = read_request(address + offset as u16, read_length as u8);
+        let (reply, size) = connection.transaction(&data)?;
+        response.extend_from_slice(read_payload(&reply, size, read_length)?);
+        remaining -= read_length;
     }
-    Err(format!("Object {} not found", id))
+    Ok(response)
 }
 
-#[tauri::command]
-fn read_object(connection_state: State<Mutex<ConnectionState>>, id: u8) -> Result<String, String> {
-    let mut connection = connection_state.lock();
-    let data = read_object_impl(&connection, id)?;
-    match id {
-        7 => {
-            let t7 = T7PowerConfig::ref_from_prefix(&data).expect("Could not create T7PowerConfig");
-            let json_str = serde_json::to_string(&t7).expect("Could not serialize T7PowerConfig");
-            return Ok(json_str);
-        }
-        8 => {
-            let t8 = T8AcquisitionConfig::ref_from_prefix(&data).expect("Could not create T8AcquisitionConfig");
-            let json_str = serde_json::to_string(&t8).expect("Could not serialize T8AcquisitionConfig");
-            return Ok(json_str);
-        }
-        25 => {
-            let t25 = T25SelfTest::ref_from_prefix(&data).expect("Could not create T25SelfTest");
-            let json_str = serde_json::to_string(&t25).expect("Could not serialize T25SelfTest");
-            return Ok(json_str);
-        }
-        42 => {
-            let t42 = T42TouchSupression::ref_from_prefix(&data).expect("Could not create T42TouchSupression");
-            let json_str = serde_json::to_string(&t42).expect("Could not serialize T42TouchSupression");
-            return Ok(json_str);
-        }
-        46 => {
-            let t46 = T46CteConfig::ref_from_prefix(&data).expect("Could not create T46CteConfig");
-            let json_str = serde_json::to_string(&t46).expect("Could not serialize T46CteConfig");
-            return Ok(json_str);
-        }
-        47 => {
-            let t47 = T47ProciStylus::ref_from_prefix(&data).expect("Could not create T47ProciStylus");
-            let json_str = serde_json::to_string(&t47).expect("Could not serialize T47ProciStylus");
-            return Ok(json_str);
+fn write_data(connection: &ConnectionState, address: u16, data: &[u8]) -> Result<(), MaxTouchError> {
+    connection.check_access(address, data.len())?;
+    let mut packet: [u8; REPORT_LENGTH] = [0; REPORT_LENGTH];
+    let mut remaining = data.len();
+    for offset in (0..data.len()).step_by(REPORT_LENGTH - 5) {
+        let write_length = cmp::min(remaining, REPORT_LENGTH - 5);
+        packet[0] = 0x0; // First byte of the first message is the Report ID
+        packet[1] = MaxTouchCommand::Write as u8;               // Command
+        packet[2] = ((address + offset as u16) & 0xff) as u8;   // Address Low
+        packet[3] = ((address + offset as u16) >> 8) as u8;     // Address High
+        packet[4] = write_length as u8;                         // Length
+        packet[5..(5 + write_length)].clone_from_slice(&data[offset..(offset + write_length)]);
+
+        let (reply, _) = connection.transaction(&packet)?;
+        if reply[0] != MaxTouchStatus::OK as u8 {
+            return Err(MaxTouchError::DeviceStatus(reply[0]));
         }
+        remaining -= write_length;
+    }
+    Ok(())
+}
+
+fn read_object_impl(connection: &ConnectionState, id: u8, instance: u16) -> Result<Vec<u8>, MaxTouchError> {
+    match connection.object_table.get(&id) {
+        Some(object) => read_data(connection, object.instance_address(instance)?, object.size),
+        None => Err(MaxTouchError::ObjectNotFound(id)),
+    }
+}
+
+fn write_object_impl(connection: &ConnectionState, id: u8, instance: u16, data: &[u8]) -> Result<(), MaxTouchError> {
+    match connection.object_table.get(&id) {
+        Some(object) => write_data(connection, object.instance_address(instance)?, data),
+        None => Err(MaxTouchError::ObjectNotFound(id)),
+    }
+}
+
+/// The registers of an object instance decoded into named fields.
+#[derive(Serialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum DecodedObject {
+    T7(T7PowerConfig),
+    T8(T8AcquisitionConfig),
+    T25(T25SelfTest),
+    T42(T42TouchSupression),
+    T46(T46CteConfig),
+    T47(T47ProciStylus),
+    T56(T56Shieldless),
+    T65(T65LensBending),
+    T80(T80RetransmissionCompensation),
+    T100(T100MultipleTouchTouchscreen),
+}
+
+/// Decode the registers of one object instance, for the objects described in `maxtouch`.
+fn decode_object(id: u8, data: &[u8]) -> Result<DecodedObject, MaxTouchError> {
+    let decoded = match id {
+        7 => DecodedObject::T7(T7PowerConfig::read_from_prefix(data).ok_or(MaxTouchError::ShortRead)?),
+        8 => DecodedObject::T8(T8AcquisitionConfig::read_from_prefix(data).ok_or(MaxTouchError::ShortRead)?),
+        25 => DecodedObject::T25(T25SelfTest::read_from_prefix(data).ok_or(MaxTouchError::ShortRead)?),
+        42 => DecodedObject::T42(T42TouchSupression::read_from_prefix(data).ok_or(MaxTouchError::ShortRead)?),
+        46 => DecodedObject::T46(T46CteConfig::read_from_prefix(data).ok_or(MaxTouchError::ShortRead)?),
+        47 => DecodedObject::T47(T47ProciStylus::read_from_prefix(data).ok_or(MaxTouchError::ShortRead)?),
         56 => {
             // This object is variable length, for now pad it up to the object size for the 1066 IC.
             let mut data_padded = Vec::from(data);
             data_padded.resize(mem::size_of::<T56Shieldless>(), 0);
-            let t56 = T56Shieldless::ref_from_prefix(&data_padded).expect("Could not create T56Shieldless");
-            let json_str = serde_json::to_string(&t56).expect("Could not serialize T56Shieldless");
-            return Ok(json_str);
-        }
-        65 => {
-            let t65 = T65LensBending::ref_from_prefix(&data).expect("Could not create T65LensBending");
-            let json_str = serde_json::to_string(&t65).expect("Could not serialize T65LensBending");
-            return Ok(json_str);
-        }
-        80 => {
-            let t80 = T80RetransmissionCompensation::ref_from_prefix(&data).expect("Could not create T80RetransmissionCompensation");
-            let json_str = serde_json::to_string(&t80).expect("Could not serialize T80RetransmissionCompensation");
-            return Ok(json_str);
-        }
-        100 => {
-            let t100 = T100MultipleTouchTouchscreen::ref_from_prefix(&data).expect("Could not create T100MultipleTouchTouchscreen");
-            let json_str = serde_json::to_string(&t100).expect("Could not serialize T100MultipleTouchTouchscreen");
-            connection.invert_x = (t100.cfg1 & 0x80) != 0;
-            connection.invert_y = (t100.cfg1 & 0x40) != 0;
-            connection.switch_xy = (t100.cfg1 & 0x20) != 0;
-            println!("Rotation information: Invert X {}, Invert Y {}, Switch XY {}, Sensor {}x{}", connection.invert_x, connection.invert_y, connection.switch_xy, connection.sensor_size[0], connection.sensor_size[1]);
-            return Ok(json_str);
-        }
-        _ => {
-            return Err(format!("Object type {} is not serializable", id))
+            DecodedObject::T56(T56Shieldless::read_from_prefix(&data_padded).ok_or(MaxTouchError::ShortRead)?)
         }
+        65 => DecodedObject::T65(T65LensBending::read_from_prefix(data).ok_or(MaxTouchError::ShortRead)?),
+        80 => DecodedObject::T80(T80RetransmissionCompensation::read_from_prefix(data).ok_or(MaxTouchError::ShortRead)?),
+        100 => DecodedObject::T100(T100MultipleTouchTouchscreen::read_from_prefix(data).ok_or(MaxTouchError::ShortRead)?),
+        _ => return Err(MaxTouchError::UnsupportedObject(id)),
+    };
+    Ok(decoded)
+}
+
+fn read_object_json(connection: &mut ConnectionState, id: u8, instance: u16) -> Result<String, MaxTouchError> {
+    let data = read_object_impl(connection, id, instance)?;
+    let decoded = decode_object(id, &data)?;
+    // The first touchscreen instance sets the orientation of the debug image.
+    if let (DecodedObject::T100(t100), 0) = (&decoded, instance) {
+        update_touch_config(connection, t100);
+    }
+    Ok(serde_json::to_string(&decoded)?)
+}
+
+fn update_touch_config(connection: &mut ConnectionState, t100: &T100MultipleTouchTouchscreen) {
+    connection.invert_x = (t100.cfg1 & 0x80) != 0;
+    connection.invert_y = (t100.cfg1 & 0x40) != 0;
+    connection.switch_xy = (t100.cfg1 & 0x20) != 0;
+    connection.touch_config = TouchConfig::from_t100(t100);
+    println!("Rotation information: Invert X {}, Invert Y {}, Switch XY {}, Sensor {}x{}", connection.invert_x, connection.invert_y, connection.switch_xy, connection.sensor_size[0], connection.sensor_size[1]);
+}
+
+/// Reread the T100 settings which affect how touches and the debug image are interpreted.
+fn refresh_touch_config(connection: &mut ConnectionState) -> Result<(), MaxTouchError> {
+    if !connection.object_table.contains_key(&100) {
+        return Ok(());
+    }
+    // Older firmware has a shorter T100, the registers it lacks read as zero.
+    let mut data = read_object_impl(connection, 100, 0)?;
+    data.resize(cmp::max(data.len(), mem::size_of::<T100MultipleTouchTouchscreen>()), 0);
+    let t100 = T100MultipleTouchTouchscreen::read_from_prefix(&data).ok_or(MaxTouchError::ShortRead)?;
+    update_touch_config(connection, &t100);
+    Ok(())
+}
+
+/// Decode one instance of an object as JSON.
+#[tauri::command]
+async fn read_object(sessions: State<'_, Mutex<SessionManager>>, session: Option<String>, id: u8, instance: Option<u16>) -> Result<String, MaxTouchError> {
+    let worker = sessions.lock().get(session.as_deref())?;
+    worker.call(Priority::Normal, move |connection| read_object_json(connection, id, instance.unwrap_or(0))).await
+}
+
+/// The object table, in address order.
+#[tauri::command]
+async fn list_objects(sessions: State<'_, Mutex<SessionManager>>, session: Option<String>) -> Result<Vec<ObjectInfo>, MaxTouchError> {
+    let worker = sessions.lock().get(session.as_deref())?;
+    worker.call(Priority::Normal, move |connection| {
+        let mut objects: Vec<ObjectInfo> = connection.object_table.iter()
+            .map(|(id, object)| ObjectInfo {
+                object_type: *id,
+                address: object.address,
+                size: object.size,
+                instances: object.instances,
+                report_ids_per_instance: object.report_ids,
+            })
+            .collect();
+        objects.sort_by_key(|object| object.address);
+        Ok(objects)
+    }).await
+}
+
+/// Which object instance each T5 report ID belongs to.
+#[tauri::command]
+async fn get_report_ids(sessions: State<'_, Mutex<SessionManager>>, session: Option<String>) -> Result<Vec<ReportSource>, MaxTouchError> {
+    let worker = sessions.lock().get(session.as_deref())?;
+    worker.call(Priority::Normal, move |connection| Ok(connection.report_ids.sources().to_vec())).await
+}
+
+/// Read T5 every 50 ms and emit each message as an event named after its type,
+/// such as `touch-message` or `command-processor-message`. After a poll which
+/// reported touches a `touches` event lists every touch still down.
+#[tauri::command]
+async fn set_message_polling(sessions: State<'_, Mutex<SessionManager>>, session: Option<String>, enable: bool) -> Result<(), MaxTouchError> {
+    let worker = sessions.lock().get(session.as_deref())?;
+    worker.call(Priority::High, move |connection| {
+        connection.poll_messages = enable;
+        Ok(())
+    }).await
+}
+
+/// The touches currently down, as last reported by T100.
+#[tauri::command]
+async fn get_touches(sessions: State<'_, Mutex<SessionManager>>, session: Option<String>) -> Result<TouchFrame, MaxTouchError> {
+    let (session, worker) = sessions.lock().resolve(session.as_deref())?;
+    worker.call(Priority::Normal, move |connection| Ok(messages::touch_frame(connection, &session))).await
+}
+
+/// Ask the chip for its configuration checksum and compare it with one
+/// calculated from the registers read back from it.
+fn get_config_checksum_impl(connection: &mut ConnectionState, session: &str) -> Result<ConfigChecksum, MaxTouchError> {
+    // Report all has no status bit of its own. With older messages flushed
+    // the first T6 status is the one it asked for.
+    let device = t6_command(connection, session, mem::offset_of!(T6CommandProcessor, reportall), T6_REPORT_ALL,
+        Duration::from_millis(1000), |_| true)?.checksum;
+
+    let ids: Vec<u8> = connection.object_table.keys().copied()
+        .filter(|id| !NON_CONFIG_OBJECTS.contains(id))
+        .collect();
+    let dump = batch::read_objects(connection, &ids, batch::DEFAULT_PIPELINE_DEPTH)?;
+    // Objects which were not read still count towards the end of the
+    // checksummed memory, as zeros.
+    let longest = connection.object_table.values().map(|object| object.length()).max().unwrap_or(0);
+    let zeros = vec![0; longest];
+    let images: Vec<ObjectImage> = connection.object_table.iter()
+        .map(|(id, object)| ObjectImage {
+            object_type: *id,
+            address: object.address,
+            data: dump.objects.get(id).map_or(&zeros[..object.length()], Vec::as_slice),
+        })
+        .collect();
+    let local = checksum::config_crc(&images).ok_or(MaxTouchError::NoConfigObjects)?;
+    println!("Config checksum: device {:06x}, local {:06x}", device, local);
+    Ok(ConfigChecksum { device, local, matches: device == local })
+}
+
+/// Compare the configuration CRC the chip reports after a T6 report all with
+/// one calculated from the registers, the way the Linux `atmel_mxt_ts` driver
+/// does: from T71 (or T7) to the end of the last object, with T5, T6, T37 and
+/// T44 counted as zero.
+#[tauri::command]
+async fn get_config_checksum(sessions: State<'_, Mutex<SessionManager>>, session: Option<String>) -> Result<ConfigChecksum, MaxTouchError> {
+    let (session, worker) = sessions.lock().resolve(session.as_deref())?;
+    worker.call(Priority::High, move |connection| get_config_checksum_impl(connection, &session)).await
+}
+
+/// Trigger a T6 command and wait for the status message which completes it.
+fn t6_command<F>(connection: &mut ConnectionState, session: &str, offset: usize, value: u8, timeout: Duration, accept: F)
+    -> Result<CommandProcessorStatus, MaxTouchError>
+where
+    F: Fn(&CommandProcessorStatus) -> bool,
+{
+    // Flush old messages so that any status read is a response to this command.
+    messages::read_messages(connection, session)?;
+    write_register_impl(connection, 6, 0, offset as u16, &[value])?;
+    messages::wait_for_t6_status(connection, session, timeout, accept)
+}
+
+fn reset_impl(connection: &mut ConnectionState, session: &str) -> Result<CommandProcessorStatus, MaxTouchError> {
+    let status = t6_command(connection, session, mem::offset_of!(T6CommandProcessor, reset), T6_RESET,
+        Duration::from_millis(2000), |status| status.reset)?;
+    connection.touches.clear();
+    // The chip reloads its configuration from NVM, so the touch settings may
+    // have changed and the journal's old bytes no longer match the device.
+    refresh_touch_config(connection)?;
+    connection.journal.clear();
+    Ok(status)
+}
+
+/// Reset the chip and wait for the status message with the reset bit set.
+#[tauri::command]
+async fn reset(sessions: State<'_, Mutex<SessionManager>>, session: Option<String>) -> Result<CommandProcessorStatus, MaxTouchError> {
+    let (session, worker) = sessions.lock().resolve(session.as_deref())?;
+    worker.call(Priority::High, move |connection| reset_impl(connection, &session)).await
+}
+
+fn calibrate_impl(connection: &mut ConnectionState, session: &str) -> Result<CommandProcessorStatus, MaxTouchError> {
+    t6_command(connection, session, mem::offset_of!(T6CommandProcessor, calibrate), T6_CALIBRATE,
+        Duration::from_millis(1000), |status| !status.calibrating)
+}
+
+/// Calibrate and wait for the calibrating bit to clear.
+#[tauri::command]
+async fn calibrate(sessions: State<'_, Mutex<SessionManager>>, session: Option<String>) -> Result<CommandProcessorStatus, MaxTouchError> {
+    let (session, worker) = sessions.lock().resolve(session.as_deref())?;
+    worker.call(Priority::High, move |connection| calibrate_impl(connection, &session)).await
+}
+
+/// Backing up to NVM wears the flash and makes any experimental settings
+/// permanent, so it needs a token from here which is passed back to `backup_nvm`.
+#[tauri::command]
+async fn request_backup_token(sessions: State<'_, Mutex<SessionManager>>, session: Option<String>) -> Result<String, MaxTouchError> {
+    let worker = sessions.lock().get(session.as_deref())?;
+    worker.call(Priority::High, move |connection| Ok(request_backup_token_impl(connection))).await
+}
+
+fn request_backup_token_impl(connection: &mut ConnectionState) -> String {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u128(SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_nanos());
+    let token = format!("{:016x}", hasher.finish());
+    connection.backup_token = Some((token.clone(), Instant::now()));
+    token
+}
+
+fn backup_nvm_impl(connection: &mut ConnectionState, session: &str, token: &str) -> Result<CommandProcessorStatus, MaxTouchError> {
+    // Tokens are single use.
+    match connection.backup_token.take() {
+        Some((expected, issued)) if expected == token && issued.elapsed() < BACKUP_TOKEN_LIFETIME => {}
+        _ => return Err(MaxTouchError::ConfirmationRequired),
+    }
+    let status = t6_command(connection, session, mem::offset_of!(T6CommandProcessor, backupnv), T6_BACKUP_NVM,
+        Duration::from_millis(2000), |_| true)?;
+    if status.config_error {
+        return Err(MaxTouchError::CommandFailed("backup_nvm".to_string()));
+    }
+    Ok(status)
+}
+
+/// Store the configuration in NVM. Needs a token from `request_backup_token`
+/// which is valid once, for 30 seconds. Fails if the chip reports a
+/// configuration error.
+#[tauri::command]
+async fn backup_nvm(sessions: State<'_, Mutex<SessionManager>>, session: Option<String>, token: String) -> Result<CommandProcessorStatus, MaxTouchError> {
+    let (session, worker) = sessions.lock().resolve(session.as_deref())?;
+    worker.call(Priority::High, move |connection| backup_nvm_impl(connection, &session, &token)).await
+}
+
+/// Dump every object apart from T5, which would acknowledge a pending message.
+/// Adjacent objects are read as one block with several requests in flight,
+/// `pipeline_depth: 1` reads one report at a time for comparison.
+#[tauri::command]
+async fn read_all_objects(sessions: State<'_, Mutex<SessionManager>>, session: Option<String>, pipeline_depth: Option<usize>) -> Result<ObjectDump, MaxTouchError> {
+    let worker = sessions.lock().get(session.as_deref())?;
+    worker.call(Priority::Normal, move |connection| {
+        let mut ids: Vec<u8> = connection.object_table.keys()
+            .copied()
+            .filter(|id| !batch::UNREAD_OBJECTS.contains(id))
+            .collect();
+        ids.sort();
+        let dump = batch::read_objects(connection, &ids, pipeline_depth.unwrap_or(batch::DEFAULT_PIPELINE_DEPTH))?;
+        println!("Read {} objects: {:?}", dump.objects.len(), dump.stats);
+        Ok(dump)
+    }).await
+}
+
+/// Save every object to a file. `.raw` is Microchip's OBP_RAW, `.xcfg` the
+/// INI style format of their tools, anything else this tool's JSON, which
+/// also holds the decoded fields.
+#[tauri::command]
+async fn save_config(sessions: State<'_, Mutex<SessionManager>>, session: Option<String>, path: PathBuf) -> Result<ConfigFile, MaxTouchError> {
+    let worker = sessions.lock().get(session.as_deref())?;
+    worker.call(Priority::Normal, move |connection| {
+        let config = config_file::read_config(connection)?;
+        config_file::save(&path, &config)?;
+        Ok(config)
+    }).await
+}
+
+/// Apply a saved configuration. It is written to RAM only, `backup_nvm` makes it permanent.
+#[tauri::command]
+async fn load_config(sessions: State<'_, Mutex<SessionManager>>, session: Option<String>, path: PathBuf) -> Result<LoadReport, MaxTouchError> {
+    let worker = sessions.lock().get(session.as_deref())?;
+    worker.call(Priority::High, move |connection| {
+        let config = config_file::load(&path, connection)?;
+        let report = config_file::apply(connection, &config)?;
+        refresh_touch_config(connection)?;
+        println!("Loaded {} objects, {} instances differ", report.objects_written, report.mismatches.len());
+        Ok(report)
+    }).await
+}
+
+/// Compare two configurations field by field. Each side is the device, a file
+/// in any format `load_config` reads or the defaults with every register
+/// cleared. Two JSON files are compared without a device.
+#[tauri::command]
+async fn diff_config(sessions: State<'_, Mutex<SessionManager>>, session: Option<String>, old: ConfigSource, new: ConfigSource)
+    -> Result<Vec<FieldChange>, MaxTouchError> {
+    // Two JSON files can be compared with nothing connected.
+    if let (Some(old), Some(new)) = (old.read_without_device(), new.read_without_device()) {
+        return Ok(diff::diff(&old?, &new?));
     }
+    let worker = sessions.lock().get(session.as_deref())?;
+    worker.call(Priority::Normal, move |connection| {
+        Ok(diff::diff(&old.read(connection)?, &new.read(connection)?))
+    }).await
 }
 
+/// C source for the QMK driver, from a saved configuration or, without a path, from the device.
 #[tauri::command]
-fn write_register(connection_state: State<Mutex<ConnectionState>>, id: u8, offset: u8, data: Vec<u8>) -> Result<(), String> {
-    let connection = connection_state.lock();
-    if offset + data.len() as u8 >= connection.object_table[&id].size {
-        return Err(format!("Attempt to write off the end of object {}.", id));
+async fn generate_qmk_config(sessions: State<'_, Mutex<SessionManager>>, session: Option<String>, path: Option<PathBuf>,
+    style: Option<CodeStyle>) -> Result<String, MaxTouchError> {
+    let worker = sessions.lock().get(session.as_deref())?;
+    worker.call(Priority::Normal, move |connection| {
+        let config = match path {
+            Some(path) => config_file::load(&path, connection)?,
+            None => config_file::read_config(connection)?,
+        };
+        qmk::generate(&config, style.unwrap_or_default())
+    }).await
+}
+
+fn write_register_impl(connection: &ConnectionState, id: u8, instance: u16, offset: u16, data: &[u8]) -> Result<(), MaxTouchError> {
+    let object = connection.object_table.get(&id).ok_or(MaxTouchError::ObjectNotFound(id))?;
+    if offset as usize + data.len() > object.size as usize {
+        return Err(MaxTouchError::OutOfBounds);
+    }
+    write_data(connection, object.instance_address(instance)? + offset, data)
+}
+
+fn read_register_impl(connection: &ConnectionState, id: u8, instance: u16, offset: u16, length: u16) -> Result<Vec<u8>, MaxTouchError> {
+    let object = connection.object_table.get(&id).ok_or(MaxTouchError::ObjectNotFound(id))?;
+    if offset as usize + length as usize > object.size as usize {
+        return Err(MaxTouchError::OutOfBounds);
+    }
+    read_data(connection, object.instance_address(instance)? + offset, length)
+}
+
+/// Write registers, keeping the touch configuration in step with T100.
+fn apply_register_write(connection: &mut ConnectionState, id: u8, instance: u16, offset: u16, data: &[u8]) -> Result<(), MaxTouchError> {
+    write_register_impl(connection, id, instance, offset, data)?;
+    if id == 100 && instance == 0 {
+        refresh_touch_config(connection)?;
     }
-    write_data(&connection, connection.object_table[&id].address + offset as u16, &data)
+    Ok(())
+}
+
+/// Write registers of an object instance, recording the bytes they held in
+/// the session's journal.
+#[tauri::command]
+async fn write_register(sessions: State<'_, Mutex<SessionManager>>, session: Option<String>, id: u8, instance: Option<u16>, offset: u16, data: Vec<u8>) -> Result<(), MaxTouchError> {
+    let worker = sessions.lock().get(session.as_deref())?;
+    worker.call(Priority::High, move |connection| {
+        let instance = instance.unwrap_or(0);
+        let length = u16::try_from(data.len()).map_err(|_| MaxTouchError::OutOfBounds)?;
+        let old = read_register_impl(connection, id, instance, offset, length)?;
+        apply_register_write(connection, id, instance, offset, &data)?;
+        connection.journal.record(JournalEntry::new(id, instance, offset, old, data));
+        Ok(())
+    }).await
 }
 
+/// Revert the last register write. Returns the write which was undone, if there was one.
 #[tauri::command]
-fn get_debug_image(connection_state: State<Mutex<ConnectionState>>, mode: u8, low: i16, high: i16) -> Result<Response, String> {
-    let connection = connection_state.lock();
+async fn undo(sessions: State<'_, Mutex<SessionManager>>, session: Option<String>) -> Result<Option<JournalEntry>, MaxTouchError> {
+    let worker = sessions.lock().get(session.as_deref())?;
+    worker.call(Priority::High, move |connection| {
+        let Some(entry) = connection.journal.undo_entry().cloned() else {
+            return Ok(None);
+        };
+        apply_register_write(connection, entry.object_type, entry.instance, entry.offset, &entry.old)?;
+        connection.journal.mark_undone();
+        Ok(Some(entry))
+    }).await
+}
+
+/// Write the last undone register write again. Returns the write, if there was one.
+#[tauri::command]
+async fn redo(sessions: State<'_, Mutex<SessionManager>>, session: Option<String>) -> Result<Option<JournalEntry>, MaxTouchError> {
+    let worker = sessions.lock().get(session.as_deref())?;
+    worker.call(Priority::High, move |connection| {
+        let Some(entry) = connection.journal.redo_entry().cloned() else {
+            return Ok(None);
+        };
+        apply_register_write(connection, entry.object_type, entry.instance, entry.offset, &entry.new)?;
+        connection.journal.mark_redone();
+        Ok(Some(entry))
+    }).await
+}
+
+/// The register writes made in the session, including the undone ones.
+#[tauri::command]
+async fn get_journal(sessions: State<'_, Mutex<SessionManager>>, session: Option<String>) -> Result<Journal, MaxTouchError> {
+    let worker = sessions.lock().get(session.as_deref())?;
+    worker.call(Priority::Normal, move |connection| Ok(connection.journal.clone())).await
+}
+
+/// Save the writes which are still applied as a tuning log, one JSON object per line.
+#[tauri::command]
+async fn export_journal(sessions: State<'_, Mutex<SessionManager>>, session: Option<String>, path: PathBuf) -> Result<usize, MaxTouchError> {
+    let worker = sessions.lock().get(session.as_deref())?;
+    worker.call(Priority::Normal, move |connection| connection.journal.export(&path)).await
+}
+
+/// Read T37, which holds the mode and page followed by 128 bytes of data.
+fn read_diagnostic_page(connection: &ConnectionState) -> Result<Vec<u8>, MaxTouchError> {
+    let data = read_object_impl(connection, 37, 0)?;
+    if data.len() < 130 {
+        return Err(MaxTouchError::ShortRead);
+    }
+    Ok(data)
+}
+
+fn get_debug_image_impl(connection: &mut ConnectionState, mode: u8, low: i16, high: i16) -> Result<Vec<u8>, MaxTouchError> {
     let width;
     let height;
     if connection.switch_xy {
@@ -277,22 +852,23 @@ fn get_debug_image(connection_state: State<Mutex<ConnectionState>>, mode: u8, lo
 
     let mut t6: T6CommandProcessor = FromZeroes::new_zeroed();
     t6.diagnostic = mode;
-    write_object_impl(&connection, 6, t6.as_bytes())?;
+    write_object_impl(connection, 6, 0, t6.as_bytes())?;
+    connection.debug_mode = Some(mode);
     t6.diagnostic = 1; // Next page
 
     let sensor_nodes = connection.sensor_size[0] as u16 * connection.sensor_size[1] as u16;
 
-    let pages = ((sensor_nodes * 2) as f32 / 128.0).ceil() as u8;
+    let pages = ((sensor_nodes as u32 * 2) as f32 / 128.0).ceil() as u8;
     let mut min_sample = i16::MAX;
     let mut max_sample = i16::MIN;
     for page in 0..pages {
-        let mut data = read_object_impl(&connection, 37)?;
+        let mut data = read_diagnostic_page(connection)?;
         if data[0] != 37 && data[1] != page {
             // Retry if the page hasnt updated
-            data = read_object_impl(&connection, 37)?;
+            data = read_diagnostic_page(connection)?;
         }
         if page != pages - 1 {
-            write_object_impl(&connection, 6, t6.as_bytes())?;
+            write_object_impl(connection, 6, 0, t6.as_bytes())?;
         }
         for index in (0..128).step_by(2) {
             let full_index = ((page as u32 * 128) + index) / 2;
@@ -356,170 +932,647 @@ fn get_debug_image(connection_state: State<Mutex<ConnectionState>>, mode: u8, lo
 
     let encoder = PngEncoder::new(&mut encoded_image);
     encoder
-        .write_image(&img, width, height, image::ExtendedColorType::Rgb8)
-        .unwrap();
-    Ok(Response::new(encoded_image))
-}
-
-#[tauri::command]
-fn connect(connection_state: State<Mutex<ConnectionState>>) -> Result<InformationBlock, String> {
-    let mut connection = connection_state.lock();
-    connection.device = None;
-
-    match HidApi::new() {
-        Ok(api) => {
-            for device in api.device_list() {
-                if device.vendor_id() == VENDOR_ID
-                    && device.product_id() == PRODUCT_ID
-                    && device.usage() == USAGE
-                    && device.usage_page() == USAGE_PAGE
-                {
-                    println!(
-                        "Found device: {} {}",
-                        device.manufacturer_string().unwrap(),
-                        device.product_string().unwrap()
-                    );
-                    match device.open_device(&api) {
-                        Ok(device) => {
-                            connection.device = Some(device);
-                            check_version(&connection).expect("Version Check Failed");
-                            let data =
-                                read_data(&connection, 0, mem::size_of::<InformationBlock>() as u8)
-                                    .expect("Failed to read info block.");
-                            let info = InformationBlock::ref_from_prefix(&data)
-                                .expect("Could not parse info block");
-                            for index in 0..info.num_objects {
-                                let object_data = read_data(
-                                    &connection,
-                                    mem::size_of::<InformationBlock>() as u16
-                                        + mem::size_of::<ObjectTableElement>() as u16
-                                            * index as u16,
-                                    mem::size_of::<ObjectTableElement>() as u8,
-                                )
-                                .expect("Failed to read info block.");
-
-                                connection.sensor_size[0] = info.matrix_x_size;
-                                connection.sensor_size[1] = info.matrix_y_size;
-
-                                let object = ObjectTableElement::ref_from_prefix(&object_data)
-                                    .expect("Could not object table element.");
-                                connection.object_table.insert(
-                                    object.object_type,
-                                    ObjectDetails {
-                                        address: ((object.position_ms_byte as u16) << 8)
-                                            | object.position_ls_byte as u16,
-                                        size: object.size_minus_one + 1,
-                                        instances: object.instances_minus_one + 1,
-                                    },
-                                );
-                            }
-                            println!("{:?}", connection.object_table);
-                            return Ok(info.clone());
-                        }
-                        Err(e) => {
-                            return Err(format!("Error opening the device: {}", e));
-                        }
-                    }
-                }
-            }
-            Err(format!("No device found"))
+        .write_image(&img, width, height, image::ExtendedColorType::Rgb8)?;
+    Ok(encoded_image)
+}
+
+#[tauri::command]
+async fn get_debug_image(sessions: State<'_, Mutex<SessionManager>>, session: Option<String>, mode: u8, low: i16, high: i16) -> Result<Response, MaxTouchError> {
+    let worker = sessions.lock().get(session.as_deref())?;
+    // Images are polled, so a newer request makes any queued one stale.
+    let image = worker.call_latest(Priority::Low, "debug_image", move |connection| {
+        get_debug_image_impl(connection, mode, low, high)
+    }).await?;
+    Ok(Response::new(image))
+}
+
+fn initialize_device(connection: &mut ConnectionState) -> Result<InformationBlock, MaxTouchError> {
+    connection.object_table.clear();
+    connection.protocol = check_version(connection)?;
+    let data = read_data(connection, 0, mem::size_of::<InformationBlock>() as u16)?;
+    let info = InformationBlock::ref_from_prefix(&data).ok_or(MaxTouchError::ShortRead)?;
+    // Read the whole table before using it, accesses are not checked against it until it is complete.
+    let mut elements = Vec::new();
+    for index in 0..info.num_objects {
+        let object_data = read_data(
+            connection,
+            mem::size_of::<InformationBlock>() as u16
+                + mem::size_of::<ObjectTableElement>() as u16
+                    * index as u16,
+            mem::size_of::<ObjectTableElement>() as u16,
+        )?;
+        let object = ObjectTableElement::ref_from_prefix(&object_data).ok_or(MaxTouchError::ShortRead)?;
+        elements.push(object.clone());
+    }
+    apply_object_table(connection, info.clone(), elements);
+    println!("{:?}", connection.object_table);
+    refresh_touch_config(connection)?;
+    Ok(info.clone())
+}
+
+/// Set up everything which follows from the information block and object table.
+fn apply_object_table(connection: &mut ConnectionState, info: InformationBlock, elements: Vec<ObjectTableElement>) {
+    connection.sensor_size[0] = info.matrix_x_size;
+    connection.sensor_size[1] = info.matrix_y_size;
+    connection.object_table = elements.iter()
+        .map(|object| (object.object_type, ObjectDetails {
+            address: ((object.position_ms_byte as u16) << 8)
+                | object.position_ls_byte as u16,
+            size: object.size_minus_one as u16 + 1,
+            instances: object.instances_minus_one as u16 + 1,
+            report_ids: object.report_ids_per_instance,
+        }))
+        .collect();
+    connection.report_ids = ReportIdMap::build(&elements);
+    connection.elements = elements;
+    connection.info = Some(info);
+    connection.touches.clear();
+}
+
+/// Connect to a trackpad and open a session named after its path. With
+/// `MAXTOUCH_SIMULATOR` set a software maXTouch is used instead. Otherwise
+/// `path` picks an interface from `enumerate_devices`, or a device matching the
+/// filter which has no session yet is used.
+#[tauri::command]
+async fn connect(sessions: State<'_, Mutex<SessionManager>>, filter_state: State<'_, Mutex<DeviceFilter>>,
+    filter: Option<DeviceFilter>, path: Option<String>) -> Result<SessionInfo, MaxTouchError> {
+    let (session, connection) = if std::env::var_os("MAXTOUCH_SIMULATOR").is_some() {
+        println!("Connecting to the simulated device");
+        (SIMULATOR_SESSION.to_string(), ConnectionState::new(Box::new(Simulator::new())))
+    }
+    else {
+        // An explicit path from enumerate_devices takes precedence over the filter.
+        // Otherwise prefer a matching device which is not already connected, so
+        // that connecting again picks up a second trackpad.
+        let filter = filter.unwrap_or_else(|| filter_state.lock().clone());
+        let session_paths = session_paths(&sessions).await;
+        let api = HidApi::new()?;
+        let candidates: Vec<_> = api.device_list()
+            .filter(|device| match &path {
+                Some(path) => device.path().to_string_lossy() == path.as_str(),
+                None => filter.matches(device),
+            })
+            .collect();
+        let session_on = |device: &DeviceInfo| {
+            let path = device.path().to_string_lossy();
+            session_paths.iter().find(|(_, session_path)| *session_path == path).map(|(session, _)| session.clone())
+        };
+        let device = candidates.iter()
+            .find(|device| session_on(device).is_none())
+            .or(candidates.first())
+            .ok_or(MaxTouchError::DeviceNotFound)?;
+
+        println!(
+            "Found device: {} {}",
+            device.manufacturer_string().unwrap_or_default(),
+            device.product_string().unwrap_or_default()
+        );
+        // Connecting to a device which already has a session replaces it.
+        let path = device.path().to_string_lossy().into_owned();
+        let session = session_on(device).unwrap_or_else(|| path.clone());
+        let mut connection = ConnectionState::new(Box::new(HidTransport::new(device.open_device(&api)?)));
+        connection.path = Some(path);
+        connection.identity = Some(DeviceMatcher::for_device(device));
+        (session, connection)
+    };
+
+    start_session(&sessions, session, connection, |connection| initialize_device(connection).map(|_| ())).await
+}
+
+/// The device path each session is on. A session is named after the path it
+/// was connected on, but hotplug moves it when the device comes back on a
+/// new path after being reflashed.
+async fn session_paths(sessions: &Mutex<SessionManager>) -> Vec<(String, String)> {
+    let workers = sessions.lock().workers();
+    let mut paths = Vec::new();
+    for (session, worker) in workers {
+        if let Ok(Some(path)) = worker.call(Priority::High, |connection| Ok(connection.path.clone())).await {
+            paths.push((session, path));
+        }
+    }
+    paths
+}
+
+/// Set up a connection on its own worker, and only register the session once
+/// that succeeds so a device which fails the handshake is not left behind.
+/// Any existing session with the same name is replaced.
+async fn start_session<F>(sessions: &Mutex<SessionManager>, session: String, connection: ConnectionState, setup: F)
+    -> Result<SessionInfo, MaxTouchError>
+where
+    F: FnOnce(&mut ConnectionState) -> Result<(), MaxTouchError> + Send + 'static,
+{
+    let worker = DeviceWorker::spawn(&session, connection);
+    let info_session = session.clone();
+    let info = worker.call(Priority::High, move |connection| {
+        setup(connection)?;
+        connection.session_info(&info_session).ok_or(MaxTouchError::NotConnected)
+    }).await;
+    match info {
+        Ok(info) => {
+            sessions.lock().insert(session, worker);
+            Ok(info)
         }
         Err(e) => {
-            Err(format!(
-                "Error reading from the configuration interface: {}",
-                e
-            ))
+            worker.shutdown();
+            Err(e)
         }
     }
 }
 
+/// Close a session.
 #[tauri::command]
-fn reboot_bootloader(connection_state: State<Mutex<ConnectionState>>) -> Result<(), String> {
-    let connection = connection_state.lock();
-    match &connection.device {
-        Some(device) => {
-            let mut packet : [u8; REPORT_LENGTH] = [0; REPORT_LENGTH];
-            packet[1] = MaxTouchCommand::Command as u8;
-            packet[2] = MaxTouchCommandType::RebootBootloader as u8;
-            match device.write(&packet) {
-                Ok(_) => return Ok(()),
-                Err(_) => { return Err(format!("Failed to write to the device.")); }
-            }
-            // Dont expect a response, the device has rebooted
-        }
-        _ => {
-            return Err(format!("Not connected."));
-        }
+fn disconnect(sessions: State<Mutex<SessionManager>>, session: String) -> Result<(), MaxTouchError> {
+    match sessions.lock().remove(&session) {
+        true => Ok(()),
+        false => Err(MaxTouchError::SessionNotFound(session)),
     }
 }
 
+/// Every open session.
 #[tauri::command]
-fn set_mouse_mode(connection_state: State<Mutex<ConnectionState>>, enable: bool) -> Result<(), String> {
-    let connection = connection_state.lock();
-    match &connection.device {
-        Some(device) => {
-            let mut packet : [u8; REPORT_LENGTH] = [0; REPORT_LENGTH];
-            packet[1] = MaxTouchCommand::Command as u8;
-            packet[2] = MaxTouchCommandType::SetMouseMode as u8;
-            packet[3] = enable as u8;
-            match device.write(&packet) {
-                Ok(_) => {},
-                Err(_) => return Err(format!("Failed to write to the device."))
-            }
-            match device.read_timeout(&mut packet, 1000) {
-                Ok(size) => {
-                    if size > 0 && packet[0] == MaxTouchStatus::OK as u8 {
-                        return Ok(());
-                    }
-                    return Err(format!("Failed to set mouse mode. Error {}", packet[0]));
-                }
-                Err(e) => {
-                    return Err(format!("Read returned error {}", e));
-                }
-            }
+async fn list_sessions(sessions: State<'_, Mutex<SessionManager>>) -> Result<Vec<SessionInfo>, MaxTouchError> {
+    let workers = sessions.lock().workers();
+    let mut infos = Vec::new();
+    for (session, worker) in workers {
+        let info = worker.call(Priority::Normal, move |connection| Ok(connection.session_info(&session))).await?;
+        infos.extend(info);
+    }
+    Ok(infos)
+}
+
+/// Record every report of a session to a JSON lines file. The first line holds
+/// the session state, so that `replay_capture` can set up a session from it.
+#[tauri::command]
+async fn start_capture(sessions: State<'_, Mutex<SessionManager>>, session: Option<String>, path: PathBuf) -> Result<(), MaxTouchError> {
+    let worker = sessions.lock().get(session.as_deref())?;
+    worker.call(Priority::High, move |connection| {
+        // Starting a new capture replaces any running one.
+        let header = connection.capture_header().ok_or(MaxTouchError::NotConnected)?;
+        if let Some(previous) = connection.capture.replace(CaptureWriter::create(&path, header)?) {
+            previous.finish()?;
         }
-        _ => {
-            return Err(format!("Not connected."));
+        Ok(())
+    }).await
+}
+
+/// Close the capture file. Returns the number of reports recorded.
+#[tauri::command]
+async fn stop_capture(sessions: State<'_, Mutex<SessionManager>>, session: Option<String>) -> Result<usize, MaxTouchError> {
+    let worker = sessions.lock().get(session.as_deref())?;
+    worker.call(Priority::High, move |connection| {
+        match connection.capture.take() {
+            Some(capture) => capture.finish(),
+            None => Ok(0),
         }
-    }
+    }).await
 }
 
+/// Emit every report of a session, decoded, as a `protocol-log` event.
 #[tauri::command]
-fn get_mouse_mode(connection_state: State<Mutex<ConnectionState>>) -> Result<(bool), String> {
-    let connection = connection_state.lock();
-    match &connection.device {
-        Some(device) => {
-            let mut packet : [u8; REPORT_LENGTH] = [0; REPORT_LENGTH];
-            packet[1] = MaxTouchCommand::Command as u8;
-            packet[2] = MaxTouchCommandType::GetMouseMode as u8;
-            match device.write(&packet) {
-                Ok(_) => {},
-                Err(_) => return Err(format!("Failed to write to the device."))
-            }
-            match device.read_timeout(&mut packet, 1000) {
-                Ok(size) => {
-                    if size > 0 && packet[0] == MaxTouchStatus::OK as u8 {
-                        return Ok(packet[1] != 0);
-                    }
-                    return Err(format!("Failed to get mouse mode. Error {}", packet[0]));
-                }
-                Err(e) => {
-                    return Err(format!("Read returned error {}", e));
-                }
+async fn set_protocol_log(app: AppHandle, sessions: State<'_, Mutex<SessionManager>>, session: Option<String>, enable: bool) -> Result<(), MaxTouchError> {
+    let (session, worker) = sessions.lock().resolve(session.as_deref())?;
+    worker.call(Priority::High, move |connection| {
+        connection.log = match enable {
+            true => Some(ProtocolLogger::new(&session, Box::new(move |entry| {
+                let _ = app.emit("protocol-log", entry);
+            }))),
+            false => None,
+        };
+        Ok(())
+    }).await
+}
+
+/// Open a capture as a new session. Requests must match the capture exactly,
+/// so repeating the recorded actions reproduces the recorded responses.
+#[tauri::command]
+async fn replay_capture(sessions: State<'_, Mutex<SessionManager>>, path: PathBuf) -> Result<SessionInfo, MaxTouchError> {
+    let session = format!("replay:{}", path.display());
+    let (header, records) = capture::load(&path)?;
+    let connection = ConnectionState::new(Box::new(ReplayTransport::new(records)));
+    start_session(&sessions, session, connection, move |connection| {
+        match header {
+            Some(header) => connection.restore_capture_header(header),
+            // Without a header the capture has to start with the handshake.
+            None => {
+                initialize_device(connection)?;
             }
         }
-        _ => {
-            return Err(format!("Not connected."));
-        }
+        Ok(())
+    }).await
+}
+
+/// Set the timeout, retries, retry backoff and whether stale input is drained
+/// before each request. T6 writes and T5 reads are never resent.
+#[tauri::command]
+async fn set_protocol_config(sessions: State<'_, Mutex<SessionManager>>, session: Option<String>, config: ProtocolConfig) -> Result<(), MaxTouchError> {
+    let worker = sessions.lock().get(session.as_deref())?;
+    worker.call(Priority::High, move |connection| {
+        connection.protocol_config = config;
+        Ok(())
+    }).await
+}
+
+/// The timeout and retry settings of a session.
+#[tauri::command]
+async fn get_protocol_config(sessions: State<'_, Mutex<SessionManager>>, session: Option<String>) -> Result<ProtocolConfig, MaxTouchError> {
+    let worker = sessions.lock().get(session.as_deref())?;
+    worker.call(Priority::Normal, move |connection| Ok(connection.protocol_config)).await
+}
+
+/// Every raw HID interface, with whether it matches the device filter.
+#[tauri::command]
+fn enumerate_devices(filter_state: State<Mutex<DeviceFilter>>, filter: Option<DeviceFilter>) -> Result<Vec<DeviceDescriptor>, MaxTouchError> {
+    let filter = filter.unwrap_or_else(|| filter_state.lock().clone());
+    let api = HidApi::new()?;
+    Ok(devices::enumerate(&api, &filter))
+}
+
+fn reboot_bootloader_impl(connection: &ConnectionState) -> Result<(), MaxTouchError> {
+    connection.require(protocol::CAP_REBOOT_BOOTLOADER, "reboot_bootloader")?;
+    let mut packet : [u8; REPORT_LENGTH] = [0; REPORT_LENGTH];
+    packet[1] = MaxTouchCommand::Command as u8;
+    packet[2] = MaxTouchCommandType::RebootBootloader as u8;
+    if connection.protocol_config.drain {
+        connection.drain_input()?;
     }
+    connection.write_report(&packet)?;
+    // Dont expect a response, the device has rebooted
+    Ok(())
+}
+
+#[tauri::command]
+async fn reboot_bootloader(sessions: State<'_, Mutex<SessionManager>>, session: Option<String>) -> Result<(), MaxTouchError> {
+    let worker = sessions.lock().get(session.as_deref())?;
+    worker.call(Priority::High, move |connection| reboot_bootloader_impl(connection)).await
+}
+
+fn set_mouse_mode_impl(connection: &ConnectionState, enable: bool) -> Result<(), MaxTouchError> {
+    connection.require(protocol::CAP_SET_MOUSE_MODE, "set_mouse_mode")?;
+    let mut packet : [u8; REPORT_LENGTH] = [0; REPORT_LENGTH];
+    packet[1] = MaxTouchCommand::Command as u8;
+    packet[2] = MaxTouchCommandType::SetMouseMode as u8;
+    packet[3] = enable as u8;
+    let (reply, _) = connection.transaction(&packet)?;
+    if reply[0] != MaxTouchStatus::OK as u8 {
+        return Err(MaxTouchError::DeviceStatus(reply[0]));
+    }
+    Ok(())
+}
+
+#[tauri::command]
+async fn set_mouse_mode(sessions: State<'_, Mutex<SessionManager>>, session: Option<String>, enable: bool) -> Result<(), MaxTouchError> {
+    let worker = sessions.lock().get(session.as_deref())?;
+    worker.call(Priority::High, move |connection| set_mouse_mode_impl(connection, enable)).await
+}
+
+fn get_mouse_mode_impl(connection: &ConnectionState) -> Result<bool, MaxTouchError> {
+    connection.require(protocol::CAP_GET_MOUSE_MODE, "get_mouse_mode")?;
+    let mut packet : [u8; REPORT_LENGTH] = [0; REPORT_LENGTH];
+    packet[1] = MaxTouchCommand::Command as u8;
+    packet[2] = MaxTouchCommandType::GetMouseMode as u8;
+    let (reply, _) = connection.transaction(&packet)?;
+    if reply[0] != MaxTouchStatus::OK as u8 {
+        return Err(MaxTouchError::DeviceStatus(reply[0]));
+    }
+    Ok(reply[1] != 0)
+}
+
+#[tauri::command]
+async fn get_mouse_mode(sessions: State<'_, Mutex<SessionManager>>, session: Option<String>) -> Result<bool, MaxTouchError> {
+    let worker = sessions.lock().get(session.as_deref())?;
+    worker.call(Priority::Normal, move |connection| get_mouse_mode_impl(connection)).await
 }
 
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
     tauri::Builder::default()
         .plugin(tauri_plugin_shell::init())
-        .manage(Mutex::new(ConnectionState::default()))
-        .invoke_handler(tauri::generate_handler![connect, get_debug_image, write_register, read_object, reboot_bootloader, set_mouse_mode, get_mouse_mode])
+        .manage(Mutex::new(SessionManager::default()))
+        .setup(|app| {
+            let path = app.path().app_config_dir()?.join(devices::DEVICE_FILTER_FILE);
+            app.manage(Mutex::new(DeviceFilter::load_or_default(&path)));
+            hotplug::spawn(app.handle().clone());
+            messages::spawn(app.handle().clone());
+            Ok(())
+        })
+        .invoke_handler(tauri::generate_handler![connect, disconnect, list_sessions, enumerate_devices, get_debug_image, write_register, read_object, reboot_bootloader, set_mouse_mode, get_mouse_mode, start_capture, stop_capture, replay_capture, set_protocol_log, set_protocol_config, get_protocol_config, read_all_objects, list_objects, get_report_ids, set_message_polling, get_touches, get_config_checksum, reset, calibrate, request_backup_token, backup_nvm, save_config, load_config, generate_qmk_config, diff_config, undo, redo, get_journal, export_journal])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use transport::MemoryTransport;
+
+    /// A response to `request`, which the keymap builds in place over the request.
+    pub(crate) fn response(status: u8, request: &[u8], payload: &[u8]) -> Vec<u8> {
+        let mut response = vec![0; REPORT_LENGTH - 1];
+        response[0] = status;
+        response[1..4].copy_from_slice(&request[2..5]);
+        response[4..4 + payload.len()].copy_from_slice(payload);
+        response
+    }
+
+    pub(crate) fn memory_connection() -> (Arc<MemoryTransport>, ConnectionState) {
+        let transport = Arc::new(MemoryTransport::new());
+        let mut connection = ConnectionState::new(Box::new(transport.clone()));
+        connection.protocol_config.backoff_ms = 0;
+        (transport, connection)
+    }
+
+    pub(crate) fn simulator_connection() -> ConnectionState {
+        let mut connection = ConnectionState::new(Box::new(Simulator::new()));
+        initialize_device(&mut connection).unwrap();
+        connection
+    }
+
+    #[test]
+    fn read_data_splits_across_reports() {
+        let (transport, connection) = memory_connection();
+        let expected: Vec<u8> = (0..40).collect();
+        transport.push_response(&response(0, &read_request(0x100, 28), &expected[..28]));
+        transport.push_response(&response(0, &read_request(0x11C, 12), &expected[28..]));
+
+        assert_eq!(read_data(&connection, 0x100, 40).unwrap(), expected);
+        let written = transport.written();
+        assert_eq!(written.len(), 2);
+        assert_eq!(written[0][..5], [0, MaxTouchCommand::Read as u8, 0x00, 0x01, 28]);
+        assert_eq!(written[1][..5], [0, MaxTouchCommand::Read as u8, 0x1C, 0x01, 12]);
+    }
+
+    #[test]
+    fn write_data_splits_across_reports() {
+        let (transport, connection) = memory_connection();
+        let data: Vec<u8> = (0..30).collect();
+        transport.push_response(&response(0, &[0, 0, 0x00, 0x02, 28], &[]));
+        transport.push_response(&response(0, &[0, 0, 0x1C, 0x02, 2], &[]));
+
+        write_data(&connection, 0x200, &data).unwrap();
+        let written = transport.written();
+        assert_eq!(written.len(), 2);
+        assert_eq!(written[0][..5], [0, MaxTouchCommand::Write as u8, 0x00, 0x02, 28]);
+        assert_eq!(written[0][5..33], data[..28]);
+        assert_eq!(written[1][..5], [0, MaxTouchCommand::Write as u8, 0x1C, 0x02, 2]);
+        assert_eq!(written[1][5..7], data[28..]);
+    }
+
+    #[test]
+    fn device_error_status() {
+        let (transport, connection) = memory_connection();
+        transport.push_response(&response(MaxTouchStatus::Error as u8, &read_request(0x10, 4), &[]));
+        assert_eq!(read_data(&connection, 0x10, 4), Err(MaxTouchError::DeviceStatus(0xFF)));
+
+        transport.push_response(&response(MaxTouchStatus::Error as u8, &[0, 0, 0x10, 0x00, 1], &[]));
+        assert_eq!(write_data(&connection, 0x10, &[1]), Err(MaxTouchError::DeviceStatus(0xFF)));
+    }
+
+    #[test]
+    fn short_read() {
+        let (transport, connection) = memory_connection();
+        let full = response(0, &read_request(0x10, 8), &[1, 2, 3, 4, 5, 6, 7, 8]);
+        transport.push_response(&full[..6]);
+        assert_eq!(read_data(&connection, 0x10, 8), Err(MaxTouchError::ShortRead));
+    }
+
+    #[test]
+    fn mismatched_response_is_retried() {
+        let (transport, connection) = memory_connection();
+        transport.push_response(&response(0, &read_request(0x20, 2), &[9, 9]));
+        transport.push_response(&response(0, &read_request(0x10, 2), &[1, 2]));
+
+        assert_eq!(read_data(&connection, 0x10, 2).unwrap(), [1, 2]);
+        let written = transport.written();
+        assert_eq!(written.len(), 2);
+        assert_eq!(written[0], written[1]);
+    }
+
+    #[test]
+    fn retries_run_out() {
+        let (transport, connection) = memory_connection();
+        for _ in 0..=connection.protocol_config.retries {
+            transport.push_response(&response(0, &read_request(0x20, 2), &[9, 9]));
+        }
+        assert_eq!(read_data(&connection, 0x10, 2), Err(MaxTouchError::ResponseMismatch));
+    }
+
+    #[test]
+    fn keymaps_without_capabilities_support_every_command() {
+        let mut version = vec![0; REPORT_LENGTH - 1];
+        version[3..5].copy_from_slice(&protocol::PROTOCOL_VERSION.to_be_bytes());
+        let protocol = ProtocolInfo::from_response(&version);
+        for capability in [protocol::CAP_READ, protocol::CAP_WRITE, protocol::CAP_REBOOT_BOOTLOADER,
+            protocol::CAP_SET_MOUSE_MODE, protocol::CAP_GET_MOUSE_MODE] {
+            assert!(protocol.supports(capability));
+        }
+
+        version[5] = protocol::CAP_READ as u8 | protocol::CAP_WRITE as u8;
+        let protocol = ProtocolInfo::from_response(&version);
+        assert!(!protocol.supports(protocol::CAP_GET_MOUSE_MODE));
+    }
+
+    #[test]
+    fn side_effects_are_not_retried() {
+        let (transport, mut connection) = memory_connection();
+        let info = InformationBlock { family_id: 0, variant_id: 0, version: 0, build: 0,
+            matrix_x_size: 0, matrix_y_size: 0, num_objects: 2 };
+        apply_object_table(&mut connection, info, vec![
+            ObjectTableElement { object_type: 5, position_ls_byte: 0x20, position_ms_byte: 0,
+                size_minus_one: 10, instances_minus_one: 0, report_ids_per_instance: 0 },
+            ObjectTableElement { object_type: 6, position_ls_byte: 0x30, position_ms_byte: 0,
+                size_minus_one: 6, instances_minus_one: 0, report_ids_per_instance: 1 },
+        ]);
+
+        // A response to some other request, as if the answer to ours was lost.
+        transport.push_response(&response(0, &read_request(0x40, 1), &[]));
+        assert_eq!(read_data(&connection, 0x20, 11), Err(MaxTouchError::ResponseMismatch));
+        assert_eq!(transport.written().len(), 1);
+
+        assert_eq!(write_data(&connection, 0x30, &[1]), Err(MaxTouchError::Timeout));
+        assert_eq!(transport.written().len(), 2);
+
+        // Reading T6 has no side effects.
+        assert_eq!(read_data(&connection, 0x30, 7), Err(MaxTouchError::Timeout));
+        assert_eq!(transport.written().len(), 3 + connection.protocol_config.retries as usize);
+    }
+
+    #[test]
+    fn initialize_device_over_memory_transport() {
+        let (transport, mut connection) = memory_connection();
+        let mut version = vec![0; REPORT_LENGTH - 1];
+        version[1..3].copy_from_slice(&protocol::MAGIC.to_be_bytes());
+        version[3..5].copy_from_slice(&protocol::PROTOCOL_VERSION.to_be_bytes());
+        transport.push_response(&version);
+        // One T7 instance of 7 bytes at 0x20.
+        transport.push_response(&response(0, &read_request(0, 7), &[0xA4, 0x14, 0x10, 0xAA, 14, 12, 1]));
+        transport.push_response(&response(0, &read_request(7, 6), &[7, 0x20, 0x00, 6, 0, 0]));
+
+        let info = initialize_device(&mut connection).unwrap();
+        assert_eq!((info.family_id, info.num_objects), (0xA4, 1));
+        let t7 = &connection.object_table[&7];
+        assert_eq!((t7.address, t7.size, t7.instances), (0x20, 7, 1));
+        assert_eq!(connection.sensor_size, [14, 12]);
+        // Accesses are now checked against the table.
+        assert_eq!(read_data(&connection, 0x30, 1), Err(MaxTouchError::OutOfBounds));
+    }
+
+    #[test]
+    fn initialize_device_with_a_short_t100() {
+        let (transport, mut connection) = memory_connection();
+        let mut version = vec![0; REPORT_LENGTH - 1];
+        version[1..3].copy_from_slice(&protocol::MAGIC.to_be_bytes());
+        version[3..5].copy_from_slice(&protocol::PROTOCOL_VERSION.to_be_bytes());
+        transport.push_response(&version);
+        // A 20 byte T100 at 0x20, much shorter than the struct.
+        transport.push_response(&response(0, &read_request(0, 7), &[0xA4, 0x14, 0x10, 0xAA, 14, 12, 1]));
+        transport.push_response(&response(0, &read_request(7, 6), &[100, 0x20, 0x00, 19, 0, 7]));
+        let mut t100 = [0; 20];
+        t100[mem::offset_of!(T100MultipleTouchTouchscreen, tchaux)] = touch::TCHAUX_AMPL;
+        transport.push_response(&response(0, &read_request(0x20, 20), &t100));
+
+        initialize_device(&mut connection).unwrap();
+        assert_eq!(connection.touch_config.tchaux, touch::TCHAUX_AMPL);
+    }
+
+    #[test]
+    fn replay_a_capture_of_the_simulator() {
+        let mut connection = simulator_connection();
+        let path = std::env::temp_dir().join(format!("maxtouch-capture-{}.jsonl", std::process::id()));
+        connection.capture = Some(CaptureWriter::create(&path, connection.capture_header().unwrap()).unwrap());
+        write_register_impl(&connection, 7, 0, 0, &[48]).unwrap();
+        let t7 = read_object_impl(&connection, 7, 0).unwrap();
+        let recorded = connection.capture.take().unwrap().finish().unwrap();
+
+        let (header, records) = capture::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(records.len(), recorded);
+        let mut replay = ConnectionState::new(Box::new(ReplayTransport::new(records)));
+        replay.restore_capture_header(header.unwrap());
+        assert_eq!(replay.report_ids.sources(), connection.report_ids.sources());
+        assert_eq!(replay.touch_config, connection.touch_config);
+
+        write_register_impl(&replay, 7, 0, 0, &[48]).unwrap();
+        assert_eq!(read_object_impl(&replay, 7, 0).unwrap(), t7);
+        // Anything the capture did not do is refused.
+        assert!(matches!(read_object_impl(&replay, 8, 0), Err(MaxTouchError::ReplayMismatch(_))));
+    }
+
+    #[test]
+    fn initialize_device_over_simulator() {
+        let connection = simulator_connection();
+        assert!(connection.object_table.contains_key(&100));
+        assert_eq!(connection.report_ids.lookup(1).map(|source| source.object_type), Some(6));
+
+        let t7 = connection.object_table[&7].address;
+        write_data(&connection, t7, &[40, 20]).unwrap();
+        assert_eq!(read_data(&connection, t7, 2).unwrap(), [40, 20]);
+        // T100 is bigger than one report.
+        assert_eq!(read_object_impl(&connection, 100, 0).unwrap().len(), connection.object_table[&100].size as usize);
+    }
+
+    #[test]
+    fn json_configs_diff_without_a_device() {
+        let connection = simulator_connection();
+        let old_path = std::env::temp_dir().join(format!("maxtouch-old-{}.json", std::process::id()));
+        let new_path = std::env::temp_dir().join(format!("maxtouch-new-{}.json", std::process::id()));
+        config_file::save(&old_path, &config_file::read_config(&connection).unwrap()).unwrap();
+        write_register_impl(&connection, 7, 0, 0, &[48]).unwrap();
+        config_file::save(&new_path, &config_file::read_config(&connection).unwrap()).unwrap();
+        drop(connection);
+
+        let old = ConfigSource::File { path: old_path.clone() };
+        let new = ConfigSource::File { path: new_path.clone() };
+        let changes = diff::diff(&old.read_without_device().unwrap().unwrap(), &new.read_without_device().unwrap().unwrap());
+        std::fs::remove_file(&old_path).unwrap();
+        std::fs::remove_file(&new_path).unwrap();
+        assert_eq!(changes.len(), 1);
+        assert_eq!((changes[0].object_type, changes[0].field.as_str()), (7, "idleacqint"));
+
+        // The Microchip formats and the device itself still need a session.
+        assert!(ConfigSource::File { path: "config.xcfg".into() }.read_without_device().is_none());
+        assert!(ConfigSource::Device.read_without_device().is_none());
+    }
+
+    #[test]
+    fn reset_reloads_touch_config_and_clears_the_journal() {
+        let mut connection = simulator_connection();
+        let touch_config = connection.touch_config;
+        connection.touch_config = TouchConfig::default();
+        connection.journal.record(JournalEntry::new(7, 0, 0, vec![32], vec![48]));
+
+        let status = reset_impl(&mut connection, "test").unwrap();
+        assert!(status.reset);
+        assert_eq!(connection.touch_config, touch_config);
+        assert!(connection.journal.undo_entry().is_none());
+    }
+
+    #[test]
+    fn calibrate_waits_for_calibration_to_finish() {
+        let mut connection = simulator_connection();
+        let status = calibrate_impl(&mut connection, "test").unwrap();
+        assert!(!status.calibrating);
+    }
+
+    #[test]
+    fn backup_tokens_are_checked_and_single_use() {
+        let mut connection = simulator_connection();
+        assert_eq!(backup_nvm_impl(&mut connection, "test", "guess"), Err(MaxTouchError::ConfirmationRequired));
+
+        let token = request_backup_token_impl(&mut connection);
+        assert_eq!(backup_nvm_impl(&mut connection, "test", "guess"), Err(MaxTouchError::ConfirmationRequired));
+        // A wrong guess uses the token up.
+        assert_eq!(backup_nvm_impl(&mut connection, "test", &token), Err(MaxTouchError::ConfirmationRequired));
+
+        let token = request_backup_token_impl(&mut connection);
+        assert!(!backup_nvm_impl(&mut connection, "test", &token).unwrap().config_error);
+        assert_eq!(backup_nvm_impl(&mut connection, "test", &token), Err(MaxTouchError::ConfirmationRequired));
+
+        let token = request_backup_token_impl(&mut connection);
+        let issued = Instant::now().checked_sub(BACKUP_TOKEN_LIFETIME).unwrap();
+        connection.backup_token = Some((token.clone(), issued));
+        assert_eq!(backup_nvm_impl(&mut connection, "test", &token), Err(MaxTouchError::ConfirmationRequired));
+    }
+
+    #[test]
+    fn second_instance_of_an_object() {
+        let connection = simulator_connection();
+        let t100 = &connection.object_table[&100];
+        assert_eq!(t100.instances, 2);
+
+        write_register_impl(&connection, 100, 1, 1, &[0x40, 0x16]).unwrap();
+        let second = read_object_impl(&connection, 100, 1).unwrap();
+        assert_eq!((second.len(), &second[1..3]), (t100.size as usize, &[0x40, 0x16][..]));
+        // The second instance follows the first, which is left alone.
+        assert_eq!(read_data(&connection, t100.address + t100.size + 1, 2).unwrap(), [0x40, 0x16]);
+        assert_eq!(read_object_impl(&connection, 100, 0).unwrap()[1], 0x80);
+
+        assert_eq!(read_object_impl(&connection, 100, 2), Err(MaxTouchError::OutOfBounds));
+        assert_eq!(write_register_impl(&connection, 100, 2, 0, &[1]), Err(MaxTouchError::OutOfBounds));
+
+        // Each instance has its own report IDs.
+        let t100_reports: Vec<u16> = connection.report_ids.sources().iter()
+            .filter(|source| source.object_type == 100)
+            .map(|source| source.instance)
+            .collect();
+        assert_eq!(t100_reports.len(), 14);
+        assert_eq!(t100_reports[7..], [1; 7]);
+    }
+
+    #[test]
+    fn check_version_errors() {
+        let (transport, connection) = memory_connection();
+        let mut version = vec![0; REPORT_LENGTH - 1];
+        version[1..3].copy_from_slice(&protocol::MAGIC.to_be_bytes());
+        version[3..5].copy_from_slice(&protocol::PROTOCOL_VERSION.to_be_bytes());
+        version[0] = MaxTouchStatus::Error as u8;
+        transport.push_response(&version);
+        assert_eq!(check_version(&connection), Err(MaxTouchError::DeviceStatus(0xFF)));
+
+        version[0] = MaxTouchStatus::OK as u8;
+        version[3..5].copy_from_slice(&(protocol::PROTOCOL_VERSION - 1).to_be_bytes());
+        transport.push_response(&version);
+        assert_eq!(check_version(&connection),
+            Err(MaxTouchError::VersionMismatch { expected: protocol::PROTOCOL_VERSION, got: protocol::PROTOCOL_VERSION - 1 }));
+    }
+}
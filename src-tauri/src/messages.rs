@@ -0,0 +1,344 @@
+use crate::error::MaxTouchError;
+use crate::report_ids::ReportIdMap;
+use crate::session::SessionManager;
+use crate::touch::{TouchConfig, TouchFrame, TouchReport};
+use crate::worker::Priority;
+use crate::{read_object_impl, ConnectionState};
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::thread;
+use std::time::{Duration, Instant};
+use tauri::{AppHandle, Emitter, Manager, Runtime};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+/// Upper bound on messages read per poll, so a chip stuck reporting cannot
+/// starve everything else queued on the device.
+const MAX_MESSAGES: usize = 32;
+/// Report ID read from T5 when there are no messages pending.
+pub const NO_MESSAGE: u8 = 0xFF;
+
+// Values written to T6 fields to trigger commands.
+pub const T6_RESET: u8 = 0x01;
+pub const T6_BACKUP_NVM: u8 = 0x55;
+pub const T6_CALIBRATE: u8 = 0x01;
+pub const T6_REPORT_ALL: u8 = 0x01;
+
+// T6 status bits.
+pub const T6_STATUS_RESET: u8 = 0x80;
+pub const T6_STATUS_OVERFLOW: u8 = 0x40;
+pub const T6_STATUS_SIGNAL_ERROR: u8 = 0x20;
+pub const T6_STATUS_CALIBRATING: u8 = 0x10;
+pub const T6_STATUS_CONFIG_ERROR: u8 = 0x08;
+pub const T6_STATUS_COMMS_ERROR: u8 = 0x04;
+
+/// T25 result code for a self test run where every test passed.
+const T25_ALL_PASSED: u8 = 0xFE;
+
+/// T100 reserves its first two report IDs for the screen status and a
+/// reserved report, touches follow.
+const T100_FIRST_TOUCH_REPORT: u8 = 2;
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct CommandProcessorStatus {
+    pub status: u8,
+    pub reset: bool,
+    pub overflow: bool,
+    pub signal_error: bool,
+    pub calibrating: bool,
+    pub config_error: bool,
+    pub comms_error: bool,
+    /// 24 bit checksum of the configuration.
+    pub checksum: u32,
+}
+
+/// The contents of a T5 message, decoded according to the object which sent it.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum MessageData {
+    CommandProcessor(CommandProcessorStatus),
+    TouchScreenStatus { status: u8, num_touches: u8 },
+    Touch(TouchReport),
+    SelfTest { result: u8, passed: bool, info: Vec<u8> },
+    NoiseSuppression { status: u8, state: u8, peak_noise: u8 },
+    Unknown,
+}
+
+impl MessageData {
+    /// Name of the Tauri event the message is emitted as.
+    pub fn event(&self) -> &'static str {
+        match self {
+            MessageData::CommandProcessor(_) => "command-processor-message",
+            MessageData::TouchScreenStatus { .. } => "touch-screen-status-message",
+            MessageData::Touch(_) => "touch-message",
+            MessageData::SelfTest { .. } => "self-test-message",
+            MessageData::NoiseSuppression { .. } => "noise-suppression-message",
+            MessageData::Unknown => "unknown-message",
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Message {
+    pub session: String,
+    pub report_id: u8,
+    pub object_type: Option<u8>,
+    pub instance: Option<u16>,
+    /// The message bytes following the report ID.
+    pub data: Vec<u8>,
+    pub decoded: MessageData,
+}
+
+fn byte(data: &[u8], index: usize) -> u8 {
+    data.get(index).copied().unwrap_or(0)
+}
+
+/// Decode a message read from T5, which starts with its report ID.
+pub fn decode(session: &str, report_ids: &ReportIdMap, touch_config: &TouchConfig, message: &[u8]) -> Message {
+    let report_id = byte(message, 0);
+    let data = message.get(1..).unwrap_or_default();
+    let source = report_ids.lookup(report_id);
+    let decoded = match source {
+        Some(source) => match source.object_type {
+            6 => {
+                let status = byte(data, 0);
+                MessageData::CommandProcessor(CommandProcessorStatus {
+                    status,
+                    reset: status & T6_STATUS_RESET != 0,
+                    overflow: status & T6_STATUS_OVERFLOW != 0,
+                    signal_error: status & T6_STATUS_SIGNAL_ERROR != 0,
+                    calibrating: status & T6_STATUS_CALIBRATING != 0,
+                    config_error: status & T6_STATUS_CONFIG_ERROR != 0,
+                    comms_error: status & T6_STATUS_COMMS_ERROR != 0,
+                    checksum: u32::from_le_bytes([byte(data, 1), byte(data, 2), byte(data, 3), 0]),
+                })
+            }
+            25 => MessageData::SelfTest {
+                result: byte(data, 0),
+                passed: byte(data, 0) == T25_ALL_PASSED,
+                info: data.iter().skip(1).take(5).copied().collect(),
+            },
+            72 => MessageData::NoiseSuppression {
+                status: byte(data, 0),
+                state: byte(data, 1) & 0x07,
+                peak_noise: byte(data, 2),
+            },
+            100 => {
+                let first = report_ids.first_report_id(100, source.instance).unwrap_or(report_id);
+                match report_id - first {
+                    0 => MessageData::TouchScreenStatus { status: byte(data, 0), num_touches: byte(data, 1) },
+                    index if index >= T100_FIRST_TOUCH_REPORT => {
+                        MessageData::Touch(TouchReport::decode(index - T100_FIRST_TOUCH_REPORT, message, touch_config))
+                    }
+                    _ => MessageData::Unknown,
+                }
+            }
+            _ => MessageData::Unknown,
+        },
+        None => MessageData::Unknown,
+    };
+    Message {
+        session: session.to_string(),
+        report_id,
+        object_type: source.map(|source| source.object_type),
+        instance: source.map(|source| source.instance),
+        data: data.to_vec(),
+        decoded,
+    }
+}
+
+/// Read every pending message. T44 holds the number of messages waiting, if
+/// the chip has no T44 then T5 is read until it runs dry. Touch messages
+/// update the set of touches which are down.
+pub fn read_messages(connection: &mut ConnectionState, session: &str) -> Result<Vec<Message>, MaxTouchError> {
+    let pending = match connection.object_table.contains_key(&44) {
+        true => read_object_impl(connection, 44, 0)?.first().copied().unwrap_or(0) as usize,
+        false => MAX_MESSAGES,
+    };
+    let mut messages = Vec::new();
+    for _ in 0..pending.min(MAX_MESSAGES) {
+        let message = read_object_impl(connection, 5, 0)?;
+        if message.first().copied().unwrap_or(NO_MESSAGE) == NO_MESSAGE {
+            break;
+        }
+        let message = decode(session, &connection.report_ids, &connection.touch_config, &message);
+        if let MessageData::Touch(touch) = &message.decoded {
+            match touch.detect {
+                true => connection.touches.insert(touch.touch_id, touch.clone()),
+                false => connection.touches.remove(&touch.touch_id),
+            };
+        }
+        messages.push(message);
+    }
+    Ok(messages)
+}
+
+/// Read messages until the command processor reports a status accepted by
+/// `accept`, or `timeout` passes. Other messages read meanwhile are dropped.
+/// Error statuses from the keymap are retried, the chip does not answer
+/// while it is resetting.
+pub fn wait_for_t6_status<F>(connection: &mut ConnectionState, session: &str, timeout: Duration, accept: F)
+    -> Result<CommandProcessorStatus, MaxTouchError>
+where
+    F: Fn(&CommandProcessorStatus) -> bool,
+{
+    let start = Instant::now();
+    loop {
+        let messages = match read_messages(connection, session) {
+            Ok(messages) => messages,
+            Err(MaxTouchError::DeviceStatus(_)) => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        for message in messages {
+            if let MessageData::CommandProcessor(status) = message.decoded {
+                if accept(&status) {
+                    return Ok(status);
+                }
+            }
+        }
+        if start.elapsed() > timeout {
+            return Err(MaxTouchError::Timeout);
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/// The touches which are currently down.
+pub fn touch_frame(connection: &ConnectionState, session: &str) -> TouchFrame {
+    TouchFrame {
+        session: session.to_string(),
+        xrange: connection.touch_config.xrange,
+        yrange: connection.touch_config.yrange,
+        touches: connection.touches.values().cloned().collect(),
+    }
+}
+
+/// Start a background thread which reads the messages of every session with
+/// message polling turned on, emitting each one as an event named after its
+/// type. A `touches` event with every touch still down follows any touch messages.
+pub fn spawn<R: Runtime>(app: AppHandle<R>) {
+    thread::spawn(move || loop {
+        thread::sleep(POLL_INTERVAL);
+        let workers = app.state::<Mutex<SessionManager>>().lock().workers();
+        for (session, worker) in workers {
+            let polled_session = session.clone();
+            let result = worker.call_blocking(Priority::Low, move |connection| {
+                if !connection.poll_messages || connection.device.is_none() {
+                    return Ok((Vec::new(), None));
+                }
+                let messages = read_messages(connection, &polled_session)?;
+                let touched = messages.iter().any(|message| matches!(message.decoded, MessageData::Touch(_)));
+                let frame = touched.then(|| touch_frame(connection, &polled_session));
+                Ok((messages, frame))
+            });
+            match result {
+                Ok((messages, frame)) => {
+                    for message in messages {
+                        let _ = app.emit(message.decoded.event(), message);
+                    }
+                    if let Some(frame) = frame {
+                        let _ = app.emit("touches", frame);
+                    }
+                }
+                Err(e) => println!("Failed to read messages from {}: {}", session, e),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::maxtouch::ObjectTableElement;
+    use crate::tests::simulator_connection;
+    use crate::touch::TouchEvent;
+
+    fn element(object_type: u8, instances: u8, report_ids: u8) -> ObjectTableElement {
+        ObjectTableElement {
+            object_type,
+            position_ls_byte: 0,
+            position_ms_byte: 0,
+            size_minus_one: 0,
+            instances_minus_one: instances - 1,
+            report_ids_per_instance: report_ids,
+        }
+    }
+
+    /// Report IDs: T6 1, T25 2, T72 3, T100 instance 0 4-7 and instance 1 8-11.
+    fn report_ids() -> ReportIdMap {
+        ReportIdMap::build(&[element(6, 1, 1), element(7, 1, 0), element(25, 1, 1), element(72, 1, 1), element(100, 2, 4)])
+    }
+
+    fn decoded(message: &[u8]) -> Message {
+        decode("test", &report_ids(), &TouchConfig::default(), message)
+    }
+
+    #[test]
+    fn command_processor_status() {
+        let message = decoded(&[1, 0x98, 0x56, 0x34, 0x12]);
+        assert_eq!((message.object_type, message.instance), (Some(6), Some(0)));
+        assert_eq!(message.decoded, MessageData::CommandProcessor(CommandProcessorStatus {
+            status: 0x98,
+            reset: true,
+            overflow: false,
+            signal_error: false,
+            calibrating: true,
+            config_error: true,
+            comms_error: false,
+            checksum: 0x123456,
+        }));
+        let MessageData::CommandProcessor(status) = decoded(&[1, 0x64, 0, 0, 0]).decoded else {
+            panic!("not a T6 status");
+        };
+        assert_eq!((status.reset, status.overflow, status.signal_error, status.comms_error), (false, true, true, true));
+    }
+
+    #[test]
+    fn self_test_result() {
+        assert_eq!(decoded(&[2, 0xFE, 1, 2, 3, 4, 5, 6]).decoded,
+            MessageData::SelfTest { result: 0xFE, passed: true, info: vec![1, 2, 3, 4, 5] });
+        assert_eq!(decoded(&[2, 0x12, 7]).decoded, MessageData::SelfTest { result: 0x12, passed: false, info: vec![7] });
+    }
+
+    #[test]
+    fn noise_suppression() {
+        assert_eq!(decoded(&[3, 0x10, 0x0B, 40]).decoded,
+            MessageData::NoiseSuppression { status: 0x10, state: 3, peak_noise: 40 });
+    }
+
+    #[test]
+    fn t100_reports_are_attributed_to_their_instance() {
+        let status = decoded(&[8, 0x00, 2]);
+        assert_eq!((status.object_type, status.instance), (Some(100), Some(1)));
+        assert_eq!(status.decoded, MessageData::TouchScreenStatus { status: 0, num_touches: 2 });
+
+        let MessageData::Touch(touch) = decoded(&[7, 0x94, 0x10, 0x00, 0x20, 0x00]).decoded else {
+            panic!("not a touch");
+        };
+        assert_eq!((touch.touch_id, touch.event, touch.x, touch.y), (1, TouchEvent::Down, 0x10, 0x20));
+        let touch = decoded(&[10, 0x91, 0, 0, 0, 0]);
+        assert_eq!(touch.instance, Some(1));
+        assert!(matches!(touch.decoded, MessageData::Touch(TouchReport { touch_id: 0, .. })));
+
+        // The second T100 report is reserved, and IDs past the table belong to nothing.
+        assert_eq!(decoded(&[5, 0]).decoded, MessageData::Unknown);
+        let unknown = decoded(&[12, 0]);
+        assert_eq!((unknown.object_type, unknown.decoded), (None, MessageData::Unknown));
+    }
+
+    #[test]
+    fn read_messages_drains_the_simulator_queue() {
+        let mut connection = simulator_connection();
+        // The simulator announces its reset at power up.
+        let messages = read_messages(&mut connection, "test").unwrap();
+        assert!(matches!(&messages[..], [Message { decoded: MessageData::CommandProcessor(CommandProcessorStatus { reset: true, .. }), .. }]));
+
+        // With nothing else to say it reports its touches.
+        let messages = read_messages(&mut connection, "test").unwrap();
+        let Some(MessageData::TouchScreenStatus { num_touches, .. }) = messages.first().map(|message| &message.decoded) else {
+            panic!("no touch screen status");
+        };
+        let touches = messages.iter().filter(|message| matches!(message.decoded, MessageData::Touch(_))).count();
+        assert_eq!((touches, *num_touches), (2, 2));
+        assert_eq!(connection.touches.len(), touches);
+    }
+}
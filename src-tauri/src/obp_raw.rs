@@ -0,0 +1,115 @@
+//! Microchip's OBP_RAW text format, as used by mxt-app and the Linux
+//! `atmel_mxt_ts` driver:
+//!
+//! ```text
+//! OBP_RAW V1
+//! <family> <variant> <version> <build> <matrix x> <matrix y> <objects>
+//! <info block crc>
+//! <config crc>
+//! <type> <instance> <size> <bytes>...
+//! ```
+//!
+//! Every number is hex.
+
+use crate::checksum::NON_CONFIG_OBJECTS;
+use crate::config_file::{self, ConfigFile, InstanceContents};
+use crate::error::MaxTouchError;
+use crate::maxtouch::InformationBlock;
+use crate::ConnectionState;
+
+const HEADER: &str = "OBP_RAW V1";
+
+pub fn write(config: &ConfigFile) -> String {
+    let info = &config.info;
+    let mut text = format!("{}\n", HEADER);
+    text += &format!("{:02X} {:02X} {:02X} {:02X} {:02X} {:02X} {:02X}\n", info.family_id, info.variant_id,
+        info.version, info.build, info.matrix_x_size, info.matrix_y_size, info.num_objects);
+    text += &format!("{:06X}\n", config.info_crc());
+    text += &format!("{:06X}\n", config.config_crc().unwrap_or(0));
+    for object in &config.objects {
+        if NON_CONFIG_OBJECTS.contains(&object.object_type) {
+            continue;
+        }
+        for data in &object.data {
+            text += &format!("{:04X} {:04X} {:04X}", object.object_type, data.instance, data.raw.len());
+            for byte in &data.raw {
+                text += &format!(" {:02X}", byte);
+            }
+            text += "\n";
+        }
+    }
+    text
+}
+
+fn parse_error(line: usize, message: &str) -> MaxTouchError {
+    MaxTouchError::Encoding(format!("OBP_RAW line {}: {}", line + 1, message))
+}
+
+fn parse_hex<T>(line: usize, token: Option<&str>) -> Result<T, MaxTouchError>
+where
+    T: TryFrom<u32>,
+{
+    let token = token.ok_or_else(|| parse_error(line, "line is too short"))?;
+    u32::from_str_radix(token, 16).ok()
+        .and_then(|value| T::try_from(value).ok())
+        .ok_or_else(|| parse_error(line, &format!("bad number {}", token)))
+}
+
+/// Parse a file and map it onto the connected chip. The checksums in the
+/// file are only checked, a mismatch is reported but does not stop the load.
+pub fn read(text: &str, connection: &ConnectionState) -> Result<ConfigFile, MaxTouchError> {
+    let mut lines = text.lines().enumerate().filter(|(_, line)| !line.trim().is_empty());
+    match lines.next() {
+        Some((_, line)) if line.trim() == HEADER => {}
+        _ => return Err(MaxTouchError::Encoding(format!("Not an {} file", HEADER))),
+    }
+
+    let (number, line) = lines.next().ok_or_else(|| parse_error(1, "missing information block"))?;
+    let mut tokens = line.split_whitespace();
+    let info = InformationBlock {
+        family_id: parse_hex(number, tokens.next())?,
+        variant_id: parse_hex(number, tokens.next())?,
+        version: parse_hex(number, tokens.next())?,
+        build: parse_hex(number, tokens.next())?,
+        matrix_x_size: parse_hex(number, tokens.next())?,
+        matrix_y_size: parse_hex(number, tokens.next())?,
+        num_objects: parse_hex(number, tokens.next())?,
+    };
+    let (number, line) = lines.next().ok_or_else(|| parse_error(2, "missing information block checksum"))?;
+    let info_crc: u32 = parse_hex(number, Some(line.trim()))?;
+    let (number, line) = lines.next().ok_or_else(|| parse_error(3, "missing configuration checksum"))?;
+    let config_crc: u32 = parse_hex(number, Some(line.trim()))?;
+
+    let mut contents = Vec::new();
+    for (number, line) in lines {
+        let mut tokens = line.split_whitespace();
+        let object_type: u8 = parse_hex(number, tokens.next())?;
+        let instance: u16 = parse_hex(number, tokens.next())?;
+        let size: usize = parse_hex(number, tokens.next())?;
+        let data = tokens.map(|token| parse_hex(number, Some(token))).collect::<Result<Vec<u8>, _>>()?;
+        if data.len() != size {
+            return Err(parse_error(number, &format!("expected {} bytes, found {}", size, data.len())));
+        }
+        contents.push(InstanceContents { object_type, instance, data });
+    }
+
+    let config = config_file::assemble(connection, info, contents)?;
+    if config.info_crc() != info_crc {
+        println!("Information block checksum {:06X} in the file does not match the device layout ({:06X})", info_crc, config.info_crc());
+    }
+    if config.config_crc() != Some(config_crc) {
+        println!("Configuration checksum {:06X} in the file does not match its contents", config_crc);
+    }
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config_file::tests::assert_round_trip;
+
+    #[test]
+    fn round_trip() {
+        assert_round_trip(write, read);
+    }
+}
@@ -0,0 +1,181 @@
+use crate::{MaxTouchCommand, MaxTouchCommandType};
+use serde::{Deserialize, Serialize};
+
+pub const MAGIC: u16 = 0x9A4D;
+/// The protocol version we request, every keymap understands at least this.
+pub const PROTOCOL_VERSION: u16 = 0x0001;
+
+// Capability bits reported by the keymap in the version check response.
+pub const CAP_READ: u32 = 1 << 0;
+pub const CAP_WRITE: u32 = 1 << 1;
+pub const CAP_REBOOT_BOOTLOADER: u32 = 1 << 2;
+pub const CAP_SET_MOUSE_MODE: u32 = 1 << 3;
+pub const CAP_GET_MOUSE_MODE: u32 = 1 << 4;
+
+/// Capabilities assumed for keymaps which predate capability reporting. These
+/// are all the commands sent before the bitmap existed, a command is only
+/// refused when the keymap says it cannot handle it.
+pub const LEGACY_CAPABILITIES: u32 = CAP_READ | CAP_WRITE | CAP_REBOOT_BOOTLOADER | CAP_SET_MOUSE_MODE | CAP_GET_MOUSE_MODE;
+
+const CAPABILITY_NAMES: [(u32, &str); 5] = [
+    (CAP_READ, "read"),
+    (CAP_WRITE, "write"),
+    (CAP_REBOOT_BOOTLOADER, "reboot_bootloader"),
+    (CAP_SET_MOUSE_MODE, "set_mouse_mode"),
+    (CAP_GET_MOUSE_MODE, "get_mouse_mode"),
+];
+
+/// What the keymap told us about itself during the version check.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub struct ProtocolInfo {
+    pub version: u16,
+    pub capabilities: u32,
+}
+
+impl ProtocolInfo {
+    /// Parse a version check response. The keymap replies in place, so the
+    /// response is laid out as
+    ///   [status, magic hi, magic lo, version hi, version lo, capabilities (u32 LE)]
+    /// Older keymaps simply echo our request back, which leaves the
+    /// capabilities zeroed.
+    pub fn from_response(response: &[u8]) -> Self {
+        let version = u16::from_be_bytes([response[3], response[4]]);
+        let capabilities = u32::from_le_bytes([response[5], response[6], response[7], response[8]]);
+        ProtocolInfo {
+            version,
+            capabilities: if capabilities == 0 { LEGACY_CAPABILITIES } else { capabilities },
+        }
+    }
+
+    pub fn supports(&self, capability: u32) -> bool {
+        self.capabilities & capability == capability
+    }
+
+    pub fn capability_names(&self) -> Vec<&'static str> {
+        CAPABILITY_NAMES.iter()
+            .filter(|(capability, _)| self.supports(*capability))
+            .map(|(_, name)| *name)
+            .collect()
+    }
+}
+
+/// How requests are retried when the device does not answer, or answers
+/// something other than the request we sent.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(default)]
+pub struct ProtocolConfig {
+    /// How long to wait for each response.
+    pub timeout_ms: i32,
+    /// How many times a request is resent before giving up.
+    pub retries: u8,
+    /// Delay before the first retry, doubled for every retry after that.
+    pub backoff_ms: u64,
+    /// Discard any stale input before sending a request.
+    pub drain: bool,
+}
+
+impl Default for ProtocolConfig {
+    fn default() -> Self {
+        ProtocolConfig {
+            timeout_ms: 1000,
+            retries: 2,
+            backoff_ms: 10,
+            drain: true,
+        }
+    }
+}
+
+/// Check that a response belongs to a request. The keymap replies in place,
+/// so reads and writes echo the address and length, and the version check
+/// echoes the magic. Commands reuse those bytes for their results.
+pub fn response_matches(request: &[u8], response: &[u8]) -> bool {
+    if request.len() < 5 || response.len() < 4 {
+        return false;
+    }
+    let command = request[1];
+    if command == MaxTouchCommand::Read as u8 || command == MaxTouchCommand::Write as u8 {
+        request[2..5] == response[1..4]
+    }
+    else if command == MaxTouchCommand::CheckVersion as u8 {
+        request[2..4] == response[1..3]
+    }
+    else {
+        true
+    }
+}
+
+/// The decoded header of a report, used to annotate captures and logs.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct PacketSummary {
+    pub command: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub address: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub length: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<u8>,
+}
+
+fn command_name(command: u8, command_type: u8) -> &'static str {
+    if command == MaxTouchCommand::CheckVersion as u8 {
+        "check_version"
+    }
+    else if command == MaxTouchCommand::Read as u8 {
+        "read"
+    }
+    else if command == MaxTouchCommand::Write as u8 {
+        "write"
+    }
+    else if command == MaxTouchCommand::Command as u8 {
+        if command_type == MaxTouchCommandType::RebootBootloader as u8 {
+            "reboot_bootloader"
+        }
+        else if command_type == MaxTouchCommandType::SetMouseMode as u8 {
+            "set_mouse_mode"
+        }
+        else if command_type == MaxTouchCommandType::GetMouseMode as u8 {
+            "get_mouse_mode"
+        }
+        else {
+            "unknown_command"
+        }
+    }
+    else {
+        "unknown"
+    }
+}
+
+fn has_address(command: u8) -> bool {
+    command == MaxTouchCommand::Read as u8 || command == MaxTouchCommand::Write as u8
+}
+
+/// Decode an outgoing report, which starts with the report ID.
+pub fn summarize_request(report: &[u8]) -> PacketSummary {
+    if report.len() < 5 {
+        return PacketSummary { command: "unknown".to_string(), ..Default::default() };
+    }
+    let mut summary = PacketSummary {
+        command: command_name(report[1], report[2]).to_string(),
+        ..Default::default()
+    };
+    if has_address(report[1]) {
+        summary.address = Some(u16::from_le_bytes([report[2], report[3]]));
+        summary.length = Some(report[4]);
+    }
+    summary
+}
+
+/// Decode a response. Responses do not carry the command, so the request
+/// being answered is needed to make sense of them.
+pub fn summarize_response(report: &[u8], request: Option<&[u8]>) -> PacketSummary {
+    let mut summary = match request {
+        Some(request) => summarize_request(request),
+        None => PacketSummary { command: "unknown".to_string(), ..Default::default() },
+    };
+    summary.status = report.first().copied();
+    if summary.address.is_some() && report.len() >= 4 {
+        summary.address = Some(u16::from_le_bytes([report[1], report[2]]));
+        summary.length = Some(report[3]);
+    }
+    summary
+}
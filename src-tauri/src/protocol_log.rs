@@ -0,0 +1,141 @@
+use crate::capture::Direction;
+use crate::protocol::{self, PacketSummary};
+use crate::{MaxTouchCommand, MaxTouchStatus, ObjectDetails};
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::time::Instant;
+
+/// A decoded report, annotated with the object it touches.
+#[derive(Serialize, Debug, Clone)]
+pub struct ProtocolLogEntry {
+    pub session: String,
+    pub timestamp_us: u64,
+    pub direction: Direction,
+    #[serde(flatten)]
+    pub summary: PacketSummary,
+    /// Type of the object the address falls in, if any.
+    pub object: Option<u8>,
+    pub instance: Option<u16>,
+    /// Offset of the first register touched, relative to the object instance.
+    pub offset: Option<u16>,
+    pub payload: Vec<u8>,
+    /// Human readable form of the entry.
+    pub text: String,
+}
+
+/// Where a register address lands in the object table.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ObjectLocation {
+    pub object: u8,
+    pub instance: u16,
+    pub offset: u16,
+}
+
+/// Find the object, instance and register offset for an address.
+pub fn locate(object_table: &HashMap<u8, ObjectDetails>, address: u16) -> Option<ObjectLocation> {
+    object_table.iter().find_map(|(id, object)| {
+        let size = object.size as u32;
+        let start = object.address as u32;
+        let end = start + size * object.instances as u32;
+        let address = address as u32;
+        if size == 0 || !(start..end).contains(&address) {
+            return None;
+        }
+        Some(ObjectLocation {
+            object: *id,
+            instance: ((address - start) / size) as u16,
+            offset: ((address - start) % size) as u16,
+        })
+    })
+}
+
+/// Decode one report. Outgoing reports start with the report ID, responses
+/// do not carry a command so they are decoded against the request they answer.
+pub fn decode(session: &str, timestamp_us: u64, direction: Direction, data: &[u8], request: Option<&[u8]>,
+    object_table: &HashMap<u8, ObjectDetails>) -> ProtocolLogEntry {
+    let summary = match direction {
+        Direction::Out => protocol::summarize_request(data),
+        Direction::In => protocol::summarize_response(data, request),
+    };
+    let location = summary.address.and_then(|address| locate(object_table, address));
+
+    // Only writes carry a payload out, and only reads carry one back.
+    let command = match direction {
+        Direction::Out => data.get(1).copied(),
+        Direction::In => request.and_then(|request| request.get(1).copied()),
+    };
+    let payload_start = match (direction, command) {
+        (Direction::Out, Some(command)) if command == MaxTouchCommand::Write as u8 => Some(5),
+        (Direction::In, Some(command)) if command == MaxTouchCommand::Read as u8 => Some(4),
+        _ => None,
+    };
+    let payload = match (payload_start, summary.length) {
+        (Some(start), Some(length)) if start < data.len() => {
+            data[start..data.len().min(start + length as usize)].to_vec()
+        }
+        _ => Vec::new(),
+    };
+
+    let mut text = match direction {
+        Direction::Out => format!("> {}", summary.command),
+        Direction::In => match summary.status {
+            Some(status) if status == MaxTouchStatus::OK as u8 => format!("< {} ok", summary.command),
+            Some(status) => format!("< {} status 0x{:02x}", summary.command, status),
+            None => format!("< {}", summary.command),
+        },
+    };
+    if let (Some(address), Some(length)) = (summary.address, summary.length) {
+        text += &format!(" 0x{:04x} len {}", address, length);
+    }
+    if let Some(location) = location {
+        text += &format!(" T{}[{}]+{}", location.object, location.instance, location.offset);
+    }
+    if !payload.is_empty() {
+        text += ": ";
+        text += &payload.iter().map(|byte| format!("{:02x}", byte)).collect::<Vec<_>>().join(" ");
+    }
+
+    ProtocolLogEntry {
+        session: session.to_string(),
+        timestamp_us,
+        direction,
+        summary,
+        object: location.map(|location| location.object),
+        instance: location.map(|location| location.instance),
+        offset: location.map(|location| location.offset),
+        payload,
+        text,
+    }
+}
+
+pub type LogSink = Box<dyn Fn(ProtocolLogEntry) + Send + Sync>;
+
+/// Decodes the traffic of one session and hands each entry to a sink.
+pub struct ProtocolLogger {
+    session: String,
+    start: Instant,
+    last_request: Mutex<Option<Vec<u8>>>,
+    sink: LogSink,
+}
+
+impl ProtocolLogger {
+    pub fn new(session: &str, sink: LogSink) -> Self {
+        ProtocolLogger {
+            session: session.to_string(),
+            start: Instant::now(),
+            last_request: Mutex::new(None),
+            sink,
+        }
+    }
+
+    pub fn log(&self, direction: Direction, data: &[u8], object_table: &HashMap<u8, ObjectDetails>) {
+        let mut last_request = self.last_request.lock();
+        let timestamp_us = self.start.elapsed().as_micros() as u64;
+        let entry = decode(&self.session, timestamp_us, direction, data, last_request.as_deref(), object_table);
+        if direction == Direction::Out {
+            *last_request = Some(data.to_vec());
+        }
+        (self.sink)(entry);
+    }
+}
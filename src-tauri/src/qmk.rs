@@ -0,0 +1,167 @@
+use crate::config_file::ConfigFile;
+use crate::error::MaxTouchError;
+use crate::layout::{self, FieldLayout};
+use crate::{decode_object, xcfg};
+use serde::Deserialize;
+use serde_json::Value;
+
+/// The objects the QMK maxtouch driver configures.
+pub const QMK_OBJECTS: [u8; 9] = [7, 8, 42, 46, 47, 56, 65, 80, 100];
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum CodeStyle {
+    /// Designated initialisers for the driver's object structs.
+    #[default]
+    Struct,
+    /// `#define`s for a keyboard's `config.h`.
+    Defines,
+}
+
+/// A register, or an array of registers, to emit.
+struct Field {
+    name: String,
+    value: String,
+}
+
+/// Group the layout back into the struct fields, arrays being split per element
+/// in the layout, and format their values. Reserved fields are left out.
+fn fields(layout: &[FieldLayout], values: &Value) -> Vec<Field> {
+    let mut fields: Vec<Field> = Vec::new();
+    let mut previous = "";
+    for field in layout {
+        let name = field.name.split('[').next().unwrap_or_default();
+        if name == previous || name.starts_with("reserved") {
+            continue;
+        }
+        previous = name;
+        let value = match &values[name] {
+            Value::Array(elements) => {
+                // Only the elements which fit in the object on this chip.
+                let count = layout.iter().filter(|field| field.name.starts_with(&format!("{}[", name))).count();
+                let elements: Vec<String> = elements.iter().take(count).map(Value::to_string).collect();
+                format!("{{ {} }}", elements.join(", "))
+            }
+            value => value.to_string(),
+        };
+        fields.push(Field { name: name.to_string(), value });
+    }
+    fields
+}
+
+/// Generate C source for the objects used by the QMK driver, named after the
+/// fields in `maxtouch.rs`.
+pub fn generate(config: &ConfigFile, style: CodeStyle) -> Result<String, MaxTouchError> {
+    let info = &config.info;
+    let mut text = format!("// Generated by maxtouch-debug for family 0x{:02X} variant 0x{:02X} firmware 0x{:02X} build 0x{:02X}\n",
+        info.family_id, info.variant_id, info.version, info.build);
+    text += &format!("// Configuration checksum 0x{:06X}\n", config.config_crc().unwrap_or(0));
+
+    for object in config.objects.iter().filter(|object| QMK_OBJECTS.contains(&object.object_type)) {
+        let layout: Vec<FieldLayout> = layout::object_layout(object.object_type)
+            .ok_or(MaxTouchError::UnsupportedObject(object.object_type))?
+            .into_iter()
+            .filter(|field| field.offset + field.width as u16 <= object.size)
+            .collect();
+        let type_name = format!("mxt_{}_t{}", xcfg::object_name(object.object_type).to_ascii_lowercase(), object.object_type);
+
+        for data in &object.data {
+            // Decoded from the raw bytes, saved files do not load the decoded fields.
+            let values = serde_json::to_value(decode_object(object.object_type, &data.raw)?)?;
+            let suffix = match object.instances {
+                1 => String::new(),
+                _ => format!("_{}", data.instance),
+            };
+            text += "\n";
+            match style {
+                CodeStyle::Struct => {
+                    text += &format!("static const {} t{}{}_config = {{\n", type_name, object.object_type, suffix);
+                    for field in fields(&layout, &values) {
+                        text += &format!("    .{} = {},\n", field.name, field.value);
+                    }
+                    text += "};\n";
+                }
+                CodeStyle::Defines => {
+                    for field in fields(&layout, &values) {
+                        text += &format!("#define MXT_T{}{}_{} {}\n", object.object_type, suffix.to_ascii_uppercase(),
+                            field.name.to_ascii_uppercase(), field.value);
+                    }
+                }
+            }
+        }
+    }
+    Ok(text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config_file;
+    use crate::tests::simulator_connection;
+
+    /// T7, and a T56 cut down to 8 bytes as on a chip with four transmit pins.
+    fn config() -> ConfigFile {
+        let mut config = config_file::read_config(&simulator_connection()).unwrap();
+        config.objects.retain(|object| object.object_type == 7 || object.object_type == 56);
+        for object in &mut config.objects {
+            let raw = &mut object.data[0].raw;
+            match object.object_type {
+                7 => raw.copy_from_slice(&[32, 10, 50, 65, 0, 0, 0]),
+                _ => {
+                    object.size = 8;
+                    *raw = vec![1, 0xAA, 2, 3, 5, 6, 7, 8];
+                }
+            }
+        }
+        config
+    }
+
+    fn header(config: &ConfigFile) -> String {
+        format!("// Generated by maxtouch-debug for family 0xA4 variant 0x14 firmware 0x10 build 0xAA\n\
+            // Configuration checksum 0x{:06X}\n", config.config_crc().unwrap())
+    }
+
+    #[test]
+    fn struct_style() {
+        let config = config();
+        let expected = header(&config) + "
+static const mxt_gen_powerconfig_t7 t7_config = {
+    .idleacqint = 32,
+    .actacqint = 10,
+    .actv2idelto = 50,
+    .cfg = 65,
+    .cfg2 = 0,
+    .idleacqintfine = 0,
+    .actvaqintfine = 0,
+};
+
+static const mxt_proci_shieldless_t56 t56_config = {
+    .ctrl = 1,
+    .optint = 2,
+    .inttime = 3,
+    .intdelay = { 5, 6, 7, 8 },
+};
+";
+        assert_eq!(generate(&config, CodeStyle::Struct).unwrap(), expected);
+    }
+
+    #[test]
+    fn defines_style() {
+        let config = config();
+        let expected = header(&config) + "
+#define MXT_T7_IDLEACQINT 32
+#define MXT_T7_ACTACQINT 10
+#define MXT_T7_ACTV2IDELTO 50
+#define MXT_T7_CFG 65
+#define MXT_T7_CFG2 0
+#define MXT_T7_IDLEACQINTFINE 0
+#define MXT_T7_ACTVAQINTFINE 0
+
+#define MXT_T56_CTRL 1
+#define MXT_T56_OPTINT 2
+#define MXT_T56_INTTIME 3
+#define MXT_T56_INTDELAY { 5, 6, 7, 8 }
+";
+        assert_eq!(generate(&config, CodeStyle::Defines).unwrap(), expected);
+    }
+}
@@ -0,0 +1,63 @@
+use crate::maxtouch::ObjectTableElement;
+use serde::Serialize;
+
+/// The object instance which sends messages with a report ID.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub struct ReportSource {
+    pub report_id: u8,
+    pub object_type: u8,
+    pub instance: u16,
+}
+
+/// Maps T5 message report IDs back to the object instances which sent them.
+/// Report IDs are handed out in object table order, starting at 1, with each
+/// instance of an object taking `report_ids_per_instance` consecutive IDs.
+/// Report ID 0 is reserved and 255 means there is no message.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct ReportIdMap {
+    sources: Vec<ReportSource>,
+}
+
+impl ReportIdMap {
+    pub fn build(objects: &[ObjectTableElement]) -> Self {
+        let mut sources = Vec::new();
+        let mut report_id: u16 = 1;
+        for object in objects {
+            if object.report_ids_per_instance == 0 {
+                continue;
+            }
+            for instance in 0..=object.instances_minus_one as u16 {
+                for _ in 0..object.report_ids_per_instance {
+                    if report_id >= 0xFF {
+                        println!("Object table uses more report IDs than are available");
+                        return ReportIdMap { sources };
+                    }
+                    sources.push(ReportSource {
+                        report_id: report_id as u8,
+                        object_type: object.object_type,
+                        instance,
+                    });
+                    report_id += 1;
+                }
+            }
+        }
+        ReportIdMap { sources }
+    }
+
+    pub fn lookup(&self, report_id: u8) -> Option<ReportSource> {
+        // IDs are contiguous from 1, so the ID is the index plus one.
+        let source = self.sources.get((report_id as usize).checked_sub(1)?)?;
+        Some(*source)
+    }
+
+    /// The first report ID used by an object instance.
+    pub fn first_report_id(&self, object_type: u8, instance: u16) -> Option<u8> {
+        self.sources.iter()
+            .find(|source| source.object_type == object_type && source.instance == instance)
+            .map(|source| source.report_id)
+    }
+
+    pub fn sources(&self) -> &[ReportSource] {
+        &self.sources
+    }
+}
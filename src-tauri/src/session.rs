@@ -0,0 +1,79 @@
+use crate::error::MaxTouchError;
+use crate::maxtouch::InformationBlock;
+use crate::protocol::ProtocolInfo;
+use crate::worker::DeviceWorker;
+use serde::Serialize;
+use std::collections::HashMap;
+
+/// Session ID used for the simulated device.
+pub const SIMULATOR_SESSION: &str = "simulator";
+
+/// Summary of a connected device returned to the frontend.
+#[derive(Serialize, Debug, Clone)]
+pub struct SessionInfo {
+    pub session: String,
+    pub connected: bool,
+    pub protocol: ProtocolInfo,
+    pub capabilities: Vec<&'static str>,
+    pub info: InformationBlock,
+    pub sensor_size: [u8; 2],
+    pub invert_x: bool,
+    pub invert_y: bool,
+    pub switch_xy: bool,
+}
+
+/// Connected devices, keyed by the path of their HID interface. Each device
+/// is owned by its own I/O worker.
+#[derive(Default)]
+pub struct SessionManager {
+    sessions: HashMap<String, DeviceWorker>,
+}
+
+impl SessionManager {
+    pub fn insert(&mut self, session: String, worker: DeviceWorker) {
+        if let Some(previous) = self.sessions.insert(session, worker) {
+            previous.shutdown();
+        }
+    }
+
+    pub fn remove(&mut self, session: &str) -> bool {
+        match self.sessions.remove(session) {
+            Some(worker) => {
+                worker.shutdown();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Find the worker for the session a command refers to. If no session is
+    /// given and exactly one device is connected then that device is used.
+    pub fn get(&self, session: Option<&str>) -> Result<DeviceWorker, MaxTouchError> {
+        self.resolve(session).map(|(_, worker)| worker)
+    }
+
+    /// Like `get`, but also returns the ID of the session which was picked.
+    pub fn resolve(&self, session: Option<&str>) -> Result<(String, DeviceWorker), MaxTouchError> {
+        match session {
+            Some(session) => match self.sessions.get(session) {
+                Some(worker) => Ok((session.to_string(), worker.clone())),
+                None => Err(MaxTouchError::SessionNotFound(session.to_string())),
+            },
+            None => {
+                let mut workers = self.sessions.iter();
+                match (workers.next(), workers.next()) {
+                    (Some((session, worker)), None) => Ok((session.clone(), worker.clone())),
+                    (None, _) => Err(MaxTouchError::NotConnected),
+                    (Some(_), Some(_)) => Err(MaxTouchError::SessionRequired),
+                }
+            }
+        }
+    }
+
+    pub fn workers(&self) -> Vec<(String, DeviceWorker)> {
+        self.sessions
+            .iter()
+            .map(|(session, worker)| (session.clone(), worker.clone()))
+            .collect()
+    }
+}
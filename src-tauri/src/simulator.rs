@@ -0,0 +1,544 @@
+use crate::checksum::{self, ObjectImage};
+use crate::error::MaxTouchError;
+use crate::maxtouch::{InformationBlock, ObjectTableElement, T6CommandProcessor, T7PowerConfig,
+    T8AcquisitionConfig, T25SelfTest, T42TouchSupression, T46CteConfig, T47ProciStylus,
+    T56Shieldless, T65LensBending, T80RetransmissionCompensation, T100MultipleTouchTouchscreen};
+use crate::messages::{NO_MESSAGE, T6_BACKUP_NVM, T6_STATUS_CALIBRATING, T6_STATUS_CONFIG_ERROR, T6_STATUS_RESET};
+use crate::protocol;
+use crate::touch::{TCHAUX_AMPL, TCHAUX_AREA, TCHAUX_HW, TCHAUX_PEAK, TCHAUX_VECT};
+use crate::transport::Transport;
+use crate::{MaxTouchCommand, MaxTouchCommandType, MaxTouchStatus, REPORT_LENGTH};
+use parking_lot::Mutex;
+use std::collections::VecDeque;
+use std::{cmp, mem};
+use zerocopy::{AsBytes, FromBytes, FromZeroes};
+
+const MATRIX_X_SIZE: u8 = 14;
+const MATRIX_Y_SIZE: u8 = 12;
+const NUM_TOUCHES: u8 = 5;
+const T37_PAGE_SIZE: usize = 128;
+const SIMULATOR_PROTOCOL_VERSION: u16 = 0x0002;
+const SIMULATOR_CAPABILITIES: u32 = protocol::CAP_READ | protocol::CAP_WRITE | protocol::CAP_REBOOT_BOOTLOADER
+    | protocol::CAP_SET_MOUSE_MODE | protocol::CAP_GET_MOUSE_MODE;
+
+// T100 touch status: detect, finger type and the event in the low nibble.
+const T100_DETECT: u8 = 0x80;
+const T100_TYPE_FINGER: u8 = 0x10;
+const T100_EVENT_MOVE: u8 = 1;
+const T100_EVENT_DOWN: u8 = 4;
+
+// T6 diagnostic commands.
+const DIAGNOSTIC_PAGE_UP: u8 = 0x01;
+const DIAGNOSTIC_PAGE_DOWN: u8 = 0x02;
+const DIAGNOSTIC_DELTAS: u8 = 0x10;
+const DIAGNOSTIC_REFERENCES: u8 = 0x11;
+
+struct SimulatedObject {
+    object_type: u8,
+    address: u16,
+    size: u16,
+    instances: u8,
+    report_ids: u8,
+}
+
+struct SimulatorState {
+    memory: Vec<u8>,
+    objects: Vec<SimulatedObject>,
+    responses: VecDeque<Vec<u8>>,
+    messages: VecDeque<Vec<u8>>,
+    touching: bool,
+    mouse_mode: bool,
+    diagnostic_mode: u8,
+    diagnostic_page: u8,
+    frame: Vec<i16>,
+    frame_count: u32,
+    noise_seed: u32,
+}
+
+/// A software maXTouch behind the QMK debug keymap. The device holds a byte
+/// addressable register map with an information block, object table and a
+/// set of objects, and answers raw HID reports the way the keymap does.
+/// Diagnostic data in T37 is synthesised from a couple of moving touch blobs
+/// plus noise, and the blobs are reported as T100 touches through T5.
+pub struct Simulator {
+    state: Mutex<SimulatorState>,
+}
+
+impl Default for Simulator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Simulator {
+    pub fn new() -> Self {
+        // (type, size, instances, report IDs per instance)
+        let layout: [(u8, usize, u8, u8); 15] = [
+            (37, 2 + T37_PAGE_SIZE, 1, 0),
+            (44, 1, 1, 0),
+            (5, 11, 1, 0),
+            (6, mem::size_of::<T6CommandProcessor>(), 1, 1),
+            (38, 8, 1, 0),
+            (7, mem::size_of::<T7PowerConfig>(), 1, 0),
+            (8, mem::size_of::<T8AcquisitionConfig>(), 1, 0),
+            (25, mem::size_of::<T25SelfTest>(), 1, 1),
+            (42, mem::size_of::<T42TouchSupression>(), 1, 0),
+            (46, mem::size_of::<T46CteConfig>(), 1, 0),
+            (47, mem::size_of::<T47ProciStylus>(), 1, 0),
+            (56, mem::size_of::<T56Shieldless>(), 1, 0),
+            (65, mem::size_of::<T65LensBending>(), 1, 0),
+            (80, mem::size_of::<T80RetransmissionCompensation>(), 1, 0),
+            (100, mem::size_of::<T100MultipleTouchTouchscreen>(), 2, 2 + NUM_TOUCHES),
+        ];
+
+        let num_objects = layout.len();
+        let table_start = mem::size_of::<InformationBlock>();
+        let table_size = num_objects * mem::size_of::<ObjectTableElement>();
+        // The object table is followed by a 24 bit information block checksum.
+        let mut address = table_start + table_size + 3;
+        let mut objects = Vec::new();
+        for (object_type, size, instances, report_ids) in layout {
+            objects.push(SimulatedObject {
+                object_type,
+                address: address as u16,
+                size: size as u16,
+                instances,
+                report_ids,
+            });
+            address += size * instances as usize;
+        }
+
+        let mut state = SimulatorState {
+            memory: vec![0; address],
+            objects,
+            responses: VecDeque::new(),
+            messages: VecDeque::new(),
+            touching: false,
+            mouse_mode: false,
+            diagnostic_mode: 0,
+            diagnostic_page: 0,
+            frame: vec![0; MATRIX_X_SIZE as usize * MATRIX_Y_SIZE as usize],
+            frame_count: 0,
+            noise_seed: 0x1234_5678,
+        };
+
+        state.memory[0..table_start].copy_from_slice(&[
+            0xA4,                  // Family
+            0x14,                  // Variant
+            0x10,                  // Version
+            0xAA,                  // Build
+            MATRIX_X_SIZE,
+            MATRIX_Y_SIZE,
+            num_objects as u8,
+        ]);
+        for (index, object) in state.objects.iter().enumerate() {
+            let entry = table_start + index * mem::size_of::<ObjectTableElement>();
+            state.memory[entry..(entry + mem::size_of::<ObjectTableElement>())].copy_from_slice(&[
+                object.object_type,
+                (object.address & 0xff) as u8,
+                (object.address >> 8) as u8,
+                (object.size - 1) as u8,
+                object.instances - 1,
+                object.report_ids,
+            ]);
+        }
+        state.load_default_config();
+        // Like the real chip, announce the reset on power up.
+        state.push_t6_status(T6_STATUS_RESET);
+        Simulator { state: Mutex::new(state) }
+    }
+}
+
+impl SimulatorState {
+    fn object(&self, object_type: u8) -> Option<&SimulatedObject> {
+        self.objects.iter().find(|object| object.object_type == object_type)
+    }
+
+    fn write_object(&mut self, object_type: u8, data: &[u8]) {
+        if let Some(object) = self.object(object_type) {
+            let address = object.address as usize;
+            let length = cmp::min(data.len(), object.size as usize);
+            self.memory[address..(address + length)].copy_from_slice(&data[..length]);
+        }
+    }
+
+    /// First report ID of an object, IDs are assigned in object table order.
+    fn report_id(&self, object_type: u8) -> Option<u8> {
+        let mut report_id = 1;
+        for object in &self.objects {
+            if object.object_type == object_type {
+                return match object.report_ids {
+                    0 => None,
+                    _ => Some(report_id),
+                };
+            }
+            report_id += object.report_ids * object.instances;
+        }
+        None
+    }
+
+    /// Queue a message from the `index`th report ID of an object.
+    fn push_message(&mut self, object_type: u8, index: u8, data: &[u8]) {
+        let (report_id, size) = match (self.report_id(object_type), self.object(5)) {
+            (Some(report_id), Some(t5)) => (report_id + index, t5.size as usize),
+            _ => return,
+        };
+        let mut message = vec![0; size];
+        message[0] = report_id;
+        let length = cmp::min(data.len(), size - 1);
+        message[1..(1 + length)].copy_from_slice(&data[..length]);
+        self.messages.push_back(message);
+        self.update_message_registers();
+    }
+
+    fn config_checksum(&self) -> u32 {
+        let images: Vec<ObjectImage> = self.objects.iter()
+            .map(|object| {
+                let address = object.address as usize;
+                ObjectImage {
+                    object_type: object.object_type,
+                    address: object.address,
+                    data: &self.memory[address..(address + object.size as usize * object.instances as usize)],
+                }
+            })
+            .collect();
+        checksum::config_crc(&images).unwrap_or(0)
+    }
+
+    fn push_t6_status(&mut self, status: u8) {
+        let checksum = self.config_checksum().to_le_bytes();
+        self.push_message(6, 0, &[status, checksum[0], checksum[1], checksum[2]]);
+    }
+
+    /// Report the touch blobs as T100 touches, scaled to the T100 range and
+    /// oriented the same way as the chip would.
+    fn push_touch_messages(&mut self) {
+        let t100 = match self.object(100) {
+            Some(t100) => t100.address as usize,
+            None => return,
+        };
+        let config = T100MultipleTouchTouchscreen::read_from_prefix(&self.memory[t100..]).unwrap();
+        let touches = self.touches();
+        self.push_message(100, 0, &[0, touches.len() as u8]);
+
+        let event = match self.touching {
+            true => T100_EVENT_MOVE,
+            false => T100_EVENT_DOWN,
+        };
+        self.touching = true;
+        for (index, &(touch_x, touch_y, amplitude)) in touches.iter().enumerate() {
+            let mut x = (touch_x / (MATRIX_X_SIZE - 1) as f32).clamp(0.0, 1.0);
+            let mut y = (touch_y / (MATRIX_Y_SIZE - 1) as f32).clamp(0.0, 1.0);
+            if config.cfg1 & 0x80 != 0 {
+                x = 1.0 - x;
+            }
+            if config.cfg1 & 0x40 != 0 {
+                y = 1.0 - y;
+            }
+            if config.cfg1 & 0x20 != 0 {
+                mem::swap(&mut x, &mut y);
+            }
+            let x = (x * config.xrange as f32) as u16;
+            let y = (y * config.yrange as f32) as u16;
+            let strength = cmp::min(amplitude as u32 / 4, 255) as u8;
+            let mut message = vec![T100_DETECT | T100_TYPE_FINGER | event];
+            message.extend_from_slice(&x.to_le_bytes());
+            message.extend_from_slice(&y.to_le_bytes());
+            // Auxiliary data, in message order, for the fields enabled in tchaux.
+            let aux = [(TCHAUX_VECT, &[0][..]), (TCHAUX_AMPL, &[strength]), (TCHAUX_AREA, &[4]),
+                (TCHAUX_HW, &[2, 2]), (TCHAUX_PEAK, &[strength])];
+            for (bit, bytes) in aux {
+                if config.tchaux & bit != 0 {
+                    message.extend_from_slice(bytes);
+                }
+            }
+            self.push_message(100, 2 + index as u8, &message);
+        }
+    }
+
+    /// T44 holds the number of pending messages and T5 the oldest one.
+    fn update_message_registers(&mut self) {
+        let (t44, t5, t5_size) = match (self.object(44), self.object(5)) {
+            (Some(t44), Some(t5)) => (t44.address as usize, t5.address as usize, t5.size as usize),
+            _ => return,
+        };
+        self.memory[t44] = cmp::min(self.messages.len(), 255) as u8;
+        match self.messages.front() {
+            Some(message) => self.memory[t5..(t5 + t5_size)].copy_from_slice(message),
+            None => {
+                self.memory[t5..(t5 + t5_size)].fill(0);
+                self.memory[t5] = NO_MESSAGE;
+            }
+        }
+    }
+
+    fn before_read(&mut self, address: usize, length: usize) {
+        // Touches are reported whenever the host checks for messages and
+        // there is nothing else to say.
+        if let Some(t44) = self.object(44) {
+            let t44 = t44.address as usize;
+            if (address..address + length).contains(&t44) && self.messages.is_empty() {
+                self.push_touch_messages();
+            }
+        }
+    }
+
+    fn after_read(&mut self, address: usize, length: usize) {
+        // Reading the start of T5 acknowledges the message.
+        if let Some(t5) = self.object(5) {
+            let t5 = t5.address as usize;
+            if (address..address + length).contains(&t5) && self.messages.pop_front().is_some() {
+                self.update_message_registers();
+            }
+        }
+    }
+
+    fn load_default_config(&mut self) {
+        let mut t7: T7PowerConfig = FromZeroes::new_zeroed();
+        t7.idleacqint = 32;
+        t7.actacqint = 10;
+        t7.actv2idelto = 50;
+        self.write_object(7, t7.as_bytes());
+
+        let mut t8: T8AcquisitionConfig = FromZeroes::new_zeroed();
+        t8.chrgtime = 24;
+        t8.atchcalst = 255;
+        self.write_object(8, t8.as_bytes());
+
+        let mut t42: T42TouchSupression = FromZeroes::new_zeroed();
+        t42.ctrl = 0x01;
+        t42.maxnumtchs = NUM_TOUCHES;
+        self.write_object(42, t42.as_bytes());
+
+        let mut t46: T46CteConfig = FromZeroes::new_zeroed();
+        t46.idlesyncsperx = 16;
+        t46.activesyncsperx = 16;
+        self.write_object(46, t46.as_bytes());
+
+        let mut t100: T100MultipleTouchTouchscreen = FromZeroes::new_zeroed();
+        t100.ctrl = 0x83;
+        t100.cfg1 = 0x80;
+        t100.tchaux = TCHAUX_AMPL | TCHAUX_AREA | TCHAUX_PEAK;
+        t100.numtch = NUM_TOUCHES;
+        t100.xsize = MATRIX_X_SIZE;
+        t100.ysize = MATRIX_Y_SIZE;
+        t100.xrange = 1023;
+        t100.yrange = 1023;
+        t100.gain = 4;
+        t100.dxgain = 4;
+        t100.tchthr = 60;
+        t100.tchhyst = 15;
+        t100.movfilter = 0x42;
+        t100.jumplimit = 20;
+        self.write_object(100, t100.as_bytes());
+    }
+
+    fn handle_report(&mut self, data: &[u8]) {
+        // Strip the report ID, the keymap sees the remaining 32 bytes.
+        let mut packet = [0u8; REPORT_LENGTH - 1];
+        let length = cmp::min(data.len().saturating_sub(1), packet.len());
+        packet[..length].copy_from_slice(&data[1..(1 + length)]);
+
+        let command = packet[0];
+        if command == MaxTouchCommand::CheckVersion as u8 {
+            let mut response = packet;
+            if u16::from_be_bytes([packet[1], packet[2]]) != protocol::MAGIC
+                || u16::from_be_bytes([packet[3], packet[4]]) != protocol::PROTOCOL_VERSION {
+                response[0] = MaxTouchStatus::Error as u8;
+            }
+            else {
+                response[0] = MaxTouchStatus::OK as u8;
+                response[3..5].copy_from_slice(&SIMULATOR_PROTOCOL_VERSION.to_be_bytes());
+                response[5..9].copy_from_slice(&SIMULATOR_CAPABILITIES.to_le_bytes());
+            }
+            self.responses.push_back(response.to_vec());
+        }
+        else if command == MaxTouchCommand::Read as u8 {
+            let address = u16::from_le_bytes([packet[1], packet[2]]) as usize;
+            let length = packet[3] as usize;
+            let mut response = packet;
+            if length > REPORT_LENGTH - 5 || address + length > self.memory.len() {
+                response[0] = MaxTouchStatus::Error as u8;
+            }
+            else {
+                response[0] = MaxTouchStatus::OK as u8;
+                self.before_read(address, length);
+                response[4..(4 + length)].copy_from_slice(&self.memory[address..(address + length)]);
+                self.after_read(address, length);
+            }
+            self.responses.push_back(response.to_vec());
+        }
+        else if command == MaxTouchCommand::Write as u8 {
+            let address = u16::from_le_bytes([packet[1], packet[2]]) as usize;
+            let length = packet[3] as usize;
+            let mut response = [0u8; REPORT_LENGTH - 1];
+            response[1..4].copy_from_slice(&packet[1..4]);
+            if length > REPORT_LENGTH - 5 || address + length > self.memory.len() {
+                response[0] = MaxTouchStatus::Error as u8;
+            }
+            else {
+                response[0] = MaxTouchStatus::OK as u8;
+                self.memory[address..(address + length)].copy_from_slice(&packet[4..(4 + length)]);
+                self.on_write(address, length);
+            }
+            self.responses.push_back(response.to_vec());
+        }
+        else if command == MaxTouchCommand::Command as u8 {
+            let mut response = [0u8; REPORT_LENGTH - 1];
+            let command_type = packet[1];
+            if command_type == MaxTouchCommandType::RebootBootloader as u8 {
+                // The real device drops off the bus, there is no response.
+                return;
+            }
+            else if command_type == MaxTouchCommandType::SetMouseMode as u8 {
+                self.mouse_mode = packet[2] != 0;
+            }
+            else if command_type == MaxTouchCommandType::GetMouseMode as u8 {
+                response[1] = self.mouse_mode as u8;
+            }
+            else {
+                response[0] = MaxTouchStatus::Error as u8;
+            }
+            self.responses.push_back(response.to_vec());
+        }
+        else {
+            let mut response = [0u8; REPORT_LENGTH - 1];
+            response[0] = MaxTouchStatus::Error as u8;
+            self.responses.push_back(response.to_vec());
+        }
+    }
+
+    /// Take a T6 command if the write touched its field. Like the real chip,
+    /// command fields read back as zero once processed.
+    fn take_command(&mut self, address: usize, length: usize, offset: usize) -> Option<u8> {
+        let command_address = self.object(6)?.address as usize + offset;
+        if (address..address + length).contains(&command_address) {
+            let command = self.memory[command_address];
+            self.memory[command_address] = 0;
+            if command != 0 {
+                return Some(command);
+            }
+        }
+        None
+    }
+
+    fn on_write(&mut self, address: usize, length: usize) {
+        if self.take_command(address, length, mem::offset_of!(T6CommandProcessor, reset)).is_some() {
+            self.messages.clear();
+            self.touching = false;
+            self.diagnostic_mode = 0;
+            self.diagnostic_page = 0;
+            self.push_t6_status(T6_STATUS_RESET);
+            self.push_t6_status(0);
+        }
+        if let Some(backup) = self.take_command(address, length, mem::offset_of!(T6CommandProcessor, backupnv)) {
+            // There is no NVM to write, only the key is checked.
+            match backup {
+                T6_BACKUP_NVM => self.push_t6_status(0),
+                _ => self.push_t6_status(T6_STATUS_CONFIG_ERROR),
+            }
+        }
+        if self.take_command(address, length, mem::offset_of!(T6CommandProcessor, calibrate)).is_some() {
+            self.push_t6_status(T6_STATUS_CALIBRATING);
+            self.push_t6_status(0);
+        }
+        if self.take_command(address, length, mem::offset_of!(T6CommandProcessor, reportall)).is_some() {
+            self.push_t6_status(0);
+        }
+        if let Some(diagnostic) = self.take_command(address, length, mem::offset_of!(T6CommandProcessor, diagnostic)) {
+            self.run_diagnostic(diagnostic);
+        }
+    }
+
+    fn run_diagnostic(&mut self, diagnostic: u8) {
+        match diagnostic {
+            0 => return,
+            DIAGNOSTIC_PAGE_UP => self.diagnostic_page = self.diagnostic_page.wrapping_add(1),
+            DIAGNOSTIC_PAGE_DOWN => self.diagnostic_page = self.diagnostic_page.wrapping_sub(1),
+            DIAGNOSTIC_DELTAS | DIAGNOSTIC_REFERENCES => {
+                self.diagnostic_mode = diagnostic;
+                self.diagnostic_page = 0;
+                self.generate_frame();
+            }
+            _ => {
+                // Unsupported diagnostic mode, T37 reports an empty page.
+                self.diagnostic_mode = diagnostic;
+                self.diagnostic_page = 0;
+                self.frame.iter_mut().for_each(|sample| *sample = 0);
+            }
+        }
+        self.update_t37();
+    }
+
+    fn update_t37(&mut self) {
+        let t37_address = match self.object(37) {
+            Some(t37) => t37.address as usize,
+            None => return,
+        };
+        self.memory[t37_address] = self.diagnostic_mode;
+        self.memory[t37_address + 1] = self.diagnostic_page;
+        let first_node = self.diagnostic_page as usize * T37_PAGE_SIZE / 2;
+        for index in 0..(T37_PAGE_SIZE / 2) {
+            let sample = self.frame.get(first_node + index).copied().unwrap_or(0);
+            let offset = t37_address + 2 + index * 2;
+            self.memory[offset..(offset + 2)].copy_from_slice(&sample.to_le_bytes());
+        }
+    }
+
+    fn noise(&mut self) -> i16 {
+        // xorshift32
+        self.noise_seed ^= self.noise_seed << 13;
+        self.noise_seed ^= self.noise_seed >> 17;
+        self.noise_seed ^= self.noise_seed << 5;
+        (self.noise_seed % 17) as i16 - 8
+    }
+
+    /// Touch blobs as (x, y, amplitude) in sensor node coordinates.
+    fn touches(&self) -> Vec<(f32, f32, f32)> {
+        let t = self.frame_count as f32 * 0.15;
+        let centre_x = MATRIX_X_SIZE as f32 / 2.0;
+        let centre_y = MATRIX_Y_SIZE as f32 / 2.0;
+        vec![
+            (centre_x + 4.0 * t.cos(), centre_y + 3.0 * t.sin(), 450.0),
+            (centre_x - 3.0 * (t * 0.7).sin(), centre_y + 2.0 * (t * 1.3).cos(), 300.0),
+        ]
+    }
+
+    fn generate_frame(&mut self) {
+        self.frame_count = self.frame_count.wrapping_add(1);
+        let touches = self.touches();
+        for x in 0..MATRIX_X_SIZE as usize {
+            for y in 0..MATRIX_Y_SIZE as usize {
+                let mut signal = 0.0;
+                for &(touch_x, touch_y, amplitude) in &touches {
+                    let distance = (x as f32 - touch_x).powi(2) + (y as f32 - touch_y).powi(2);
+                    signal += amplitude * (-distance / 2.0).exp();
+                }
+                let noise = self.noise();
+                let sample = match self.diagnostic_mode {
+                    DIAGNOSTIC_REFERENCES => 16000 + (x * 40 + y * 25) as i16 - signal as i16 + noise * 4,
+                    _ => signal as i16 + noise,
+                };
+                self.frame[x * MATRIX_Y_SIZE as usize + y] = sample;
+            }
+        }
+    }
+}
+
+impl Transport for Simulator {
+    fn write(&self, data: &[u8]) -> Result<usize, MaxTouchError> {
+        self.state.lock().handle_report(data);
+        Ok(data.len())
+    }
+
+    fn read_timeout(&self, data: &mut [u8], _timeout: i32) -> Result<usize, MaxTouchError> {
+        match self.state.lock().responses.pop_front() {
+            Some(response) => {
+                let size = cmp::min(response.len(), data.len());
+                data[..size].copy_from_slice(&response[..size]);
+                Ok(size)
+            }
+            None => Ok(0),
+        }
+    }
+}
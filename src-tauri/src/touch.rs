@@ -0,0 +1,193 @@
+use crate::maxtouch::T100MultipleTouchTouchscreen;
+use serde::{Deserialize, Serialize};
+
+// T100 tchaux bits. Each enabled field adds to touch messages in this order,
+// one byte each apart from HW, which adds the height and then the width.
+pub const TCHAUX_VECT: u8 = 0x01;
+pub const TCHAUX_AMPL: u8 = 0x02;
+pub const TCHAUX_AREA: u8 = 0x04;
+pub const TCHAUX_HW: u8 = 0x08;
+pub const TCHAUX_PEAK: u8 = 0x10;
+
+/// Offset of the first auxiliary byte in a touch message, after the report
+/// ID, status and X/Y position.
+const FIRST_AUX_BYTE: usize = 6;
+
+/// The parts of the T100 configuration needed to make sense of touch messages.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct TouchConfig {
+    pub tchaux: u8,
+    pub xrange: u16,
+    pub yrange: u16,
+}
+
+impl TouchConfig {
+    pub fn from_t100(t100: &T100MultipleTouchTouchscreen) -> Self {
+        TouchConfig {
+            tchaux: t100.tchaux,
+            xrange: t100.xrange,
+            yrange: t100.yrange,
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TouchEvent {
+    None,
+    Move,
+    Unsuppress,
+    Suppress,
+    Down,
+    Up,
+    UnsuppressSuppress,
+    UnsuppressUp,
+    DownSuppress,
+    DownUp,
+    Other(u8),
+}
+
+impl TouchEvent {
+    fn from_status(status: u8) -> Self {
+        match status & 0x0F {
+            0 => TouchEvent::None,
+            1 => TouchEvent::Move,
+            2 => TouchEvent::Unsuppress,
+            3 => TouchEvent::Suppress,
+            4 => TouchEvent::Down,
+            5 => TouchEvent::Up,
+            6 => TouchEvent::UnsuppressSuppress,
+            7 => TouchEvent::UnsuppressUp,
+            8 => TouchEvent::DownSuppress,
+            9 => TouchEvent::DownUp,
+            other => TouchEvent::Other(other),
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TouchType {
+    Finger,
+    PassiveStylus,
+    HoveringFinger,
+    Glove,
+    LargeTouch,
+    Other(u8),
+}
+
+impl TouchType {
+    fn from_status(status: u8) -> Self {
+        match (status >> 4) & 0x07 {
+            1 => TouchType::Finger,
+            2 => TouchType::PassiveStylus,
+            4 => TouchType::HoveringFinger,
+            5 => TouchType::Glove,
+            6 => TouchType::LargeTouch,
+            other => TouchType::Other(other),
+        }
+    }
+}
+
+/// A decoded T100 touch message. Auxiliary fields are only present when
+/// enabled in tchaux.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct TouchReport {
+    pub touch_id: u8,
+    pub detect: bool,
+    pub touch_type: TouchType,
+    pub event: TouchEvent,
+    pub x: u16,
+    pub y: u16,
+    pub vector: Option<u8>,
+    pub amplitude: Option<u8>,
+    pub area: Option<u8>,
+    pub height: Option<u8>,
+    pub width: Option<u8>,
+    pub peak: Option<u8>,
+}
+
+impl TouchReport {
+    /// Decode a touch message, including the report ID.
+    pub fn decode(touch_id: u8, message: &[u8], config: &TouchConfig) -> Self {
+        let byte = |index: usize| message.get(index).copied().unwrap_or(0);
+        let status = byte(1);
+        let mut aux = FIRST_AUX_BYTE;
+        let mut next_aux = |bit: u8| {
+            if config.tchaux & bit == 0 {
+                return None;
+            }
+            aux += 1;
+            Some(byte(aux - 1))
+        };
+        TouchReport {
+            touch_id,
+            detect: status & 0x80 != 0,
+            touch_type: TouchType::from_status(status),
+            event: TouchEvent::from_status(status),
+            x: u16::from_le_bytes([byte(2), byte(3)]),
+            y: u16::from_le_bytes([byte(4), byte(5)]),
+            vector: next_aux(TCHAUX_VECT),
+            amplitude: next_aux(TCHAUX_AMPL),
+            area: next_aux(TCHAUX_AREA),
+            height: next_aux(TCHAUX_HW),
+            width: next_aux(TCHAUX_HW),
+            peak: next_aux(TCHAUX_PEAK),
+        }
+    }
+}
+
+/// The touches currently down on a session, sent after every poll which
+/// changed them. Positions are in the T100 range, so dividing by the range
+/// gives the position on the debug image.
+#[derive(Serialize, Debug, Clone)]
+pub struct TouchFrame {
+    pub session: String,
+    pub xrange: u16,
+    pub yrange: u16,
+    pub touches: Vec<TouchReport>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(tchaux: u8) -> TouchConfig {
+        TouchConfig { tchaux, xrange: 1023, yrange: 1023 }
+    }
+
+    // Report ID 5, detected finger moving at (0x123, 0x2AB).
+    const HEADER: [u8; 6] = [5, 0x91, 0x23, 0x01, 0xAB, 0x02];
+
+    #[test]
+    fn decode_without_aux() {
+        let report = TouchReport::decode(3, &HEADER, &config(0));
+        assert_eq!(report.touch_id, 3);
+        assert!(report.detect);
+        assert_eq!(report.touch_type, TouchType::Finger);
+        assert_eq!(report.event, TouchEvent::Move);
+        assert_eq!((report.x, report.y), (0x123, 0x2AB));
+        assert_eq!((report.vector, report.amplitude, report.area, report.peak), (None, None, None, None));
+    }
+
+    #[test]
+    fn decode_every_aux_field() {
+        let message = [&HEADER[..], &[0x11, 40, 6, 3, 2, 45]].concat();
+        let tchaux = TCHAUX_VECT | TCHAUX_AMPL | TCHAUX_AREA | TCHAUX_HW | TCHAUX_PEAK;
+        let report = TouchReport::decode(0, &message, &config(tchaux));
+        assert_eq!(report.vector, Some(0x11));
+        assert_eq!(report.amplitude, Some(40));
+        assert_eq!(report.area, Some(6));
+        assert_eq!((report.height, report.width), (Some(3), Some(2)));
+        assert_eq!(report.peak, Some(45));
+    }
+
+    #[test]
+    fn decode_skips_disabled_aux_fields() {
+        // Amplitude, height and width, peak.
+        let message = [&HEADER[..], &[40, 3, 2, 45]].concat();
+        let report = TouchReport::decode(0, &message, &config(TCHAUX_AMPL | TCHAUX_HW | TCHAUX_PEAK));
+        assert_eq!((report.vector, report.amplitude, report.area), (None, Some(40), None));
+        assert_eq!((report.height, report.width, report.peak), (Some(3), Some(2), Some(45)));
+    }
+}
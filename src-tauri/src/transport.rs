@@ -0,0 +1,97 @@
+use crate::error::MaxTouchError;
+use hidapi::HidDevice;
+use parking_lot::Mutex;
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+/// A raw report transport. Reports are written with a leading report ID byte
+/// and read back without one, matching the hidapi conventions.
+pub trait Transport: Send {
+    fn write(&self, data: &[u8]) -> Result<usize, MaxTouchError>;
+    fn read_timeout(&self, data: &mut [u8], timeout: i32) -> Result<usize, MaxTouchError>;
+}
+
+/// A shared transport, so the caller can keep a handle on it after handing it
+/// to a connection.
+impl<T: Transport + Sync> Transport for Arc<T> {
+    fn write(&self, data: &[u8]) -> Result<usize, MaxTouchError> {
+        (**self).write(data)
+    }
+
+    fn read_timeout(&self, data: &mut [u8], timeout: i32) -> Result<usize, MaxTouchError> {
+        (**self).read_timeout(data, timeout)
+    }
+}
+
+/// Transport backed by a raw HID interface.
+pub struct HidTransport {
+    device: HidDevice,
+}
+
+impl HidTransport {
+    pub fn new(device: HidDevice) -> Self {
+        HidTransport { device }
+    }
+}
+
+impl Transport for HidTransport {
+    fn write(&self, data: &[u8]) -> Result<usize, MaxTouchError> {
+        Ok(self.device.write(data)?)
+    }
+
+    fn read_timeout(&self, data: &mut [u8], timeout: i32) -> Result<usize, MaxTouchError> {
+        Ok(self.device.read_timeout(data, timeout)?)
+    }
+}
+
+/// In-memory transport which records every report written to it and answers
+/// reads from a queue of canned responses. Like a real device each response
+/// only becomes readable once a report has been written, so that draining
+/// stale input does not eat it, while stale input can be queued with
+/// `push_pending`. Nothing to read behaves like a read timeout.
+#[derive(Default)]
+pub struct MemoryTransport {
+    responses: Mutex<VecDeque<Vec<u8>>>,
+    pending: Mutex<VecDeque<Vec<u8>>>,
+    written: Mutex<Vec<Vec<u8>>>,
+}
+
+impl MemoryTransport {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn push_response(&self, response: &[u8]) {
+        self.responses.lock().push_back(response.to_vec());
+    }
+
+    /// Queue a report which can be read straight away.
+    pub fn push_pending(&self, report: &[u8]) {
+        self.pending.lock().push_back(report.to_vec());
+    }
+
+    pub fn written(&self) -> Vec<Vec<u8>> {
+        self.written.lock().clone()
+    }
+}
+
+impl Transport for MemoryTransport {
+    fn write(&self, data: &[u8]) -> Result<usize, MaxTouchError> {
+        self.written.lock().push(data.to_vec());
+        if let Some(response) = self.responses.lock().pop_front() {
+            self.pending.lock().push_back(response);
+        }
+        Ok(data.len())
+    }
+
+    fn read_timeout(&self, data: &mut [u8], _timeout: i32) -> Result<usize, MaxTouchError> {
+        match self.pending.lock().pop_front() {
+            Some(response) => {
+                let size = response.len().min(data.len());
+                data[..size].copy_from_slice(&response[..size]);
+                Ok(size)
+            }
+            None => Ok(0),
+        }
+    }
+}
@@ -0,0 +1,182 @@
+use crate::error::MaxTouchError;
+use crate::ConnectionState;
+use parking_lot::{Condvar, Mutex};
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::thread;
+use tokio::sync::oneshot;
+
+/// Request priorities, higher priority requests are always serviced first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Priority {
+    /// Register writes and device commands, anything the user is waiting on.
+    High = 0,
+    /// Register and object reads.
+    Normal = 1,
+    /// Background polling such as debug images.
+    Low = 2,
+}
+
+type Job = Box<dyn FnOnce(&mut ConnectionState) + Send>;
+
+struct QueuedJob {
+    tag: Option<&'static str>,
+    job: Job,
+}
+
+#[derive(Default)]
+struct Queue {
+    jobs: [VecDeque<QueuedJob>; 3],
+    shutdown: bool,
+}
+
+impl Queue {
+    fn pop(&mut self) -> Option<QueuedJob> {
+        self.jobs.iter_mut().find_map(|jobs| jobs.pop_front())
+    }
+}
+
+#[derive(Default)]
+struct Shared {
+    queue: Mutex<Queue>,
+    available: Condvar,
+}
+
+impl Shared {
+    fn stop(&self) {
+        let mut queue = self.queue.lock();
+        queue.shutdown = true;
+        queue.jobs.iter_mut().for_each(|jobs| jobs.clear());
+        self.available.notify_all();
+    }
+}
+
+/// Stops the queue when the I/O thread exits for any reason, a panicking
+/// job included, so that nothing is left waiting on requests which will
+/// never run.
+struct StopOnExit(Arc<Shared>);
+
+impl Drop for StopOnExit {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            println!("I/O thread {} panicked, cancelling its requests", thread::current().name().unwrap_or_default());
+        }
+        self.0.stop();
+    }
+}
+
+/// Handle to the I/O thread which owns a device. All device access goes
+/// through the thread's queue, so a slow request never blocks a command
+/// handler and requests for one device are never interleaved. Dropping a
+/// queued request resolves its caller with `MaxTouchError::Cancelled`.
+#[derive(Clone)]
+pub struct DeviceWorker {
+    shared: Arc<Shared>,
+}
+
+impl DeviceWorker {
+    pub fn spawn(name: &str, mut connection: ConnectionState) -> Self {
+        let shared = Arc::new(Shared::default());
+        let worker_shared = shared.clone();
+        let spawned = thread::Builder::new()
+            .name(format!("maxtouch-io {}", name))
+            .spawn(move || {
+                let _stop = StopOnExit(worker_shared.clone());
+                loop {
+                    let job = {
+                        let mut queue = worker_shared.queue.lock();
+                        loop {
+                            if queue.shutdown {
+                                return;
+                            }
+                            if let Some(job) = queue.pop() {
+                                break job;
+                            }
+                            worker_shared.available.wait(&mut queue);
+                        }
+                    };
+                    (job.job)(&mut connection);
+                }
+            });
+        if let Err(e) = spawned {
+            println!("Failed to start the I/O thread for {}: {}", name, e);
+            shared.queue.lock().shutdown = true;
+        }
+        DeviceWorker { shared }
+    }
+
+    fn submit<T, F>(&self, priority: Priority, tag: Option<&'static str>, job: F) -> oneshot::Receiver<Result<T, MaxTouchError>>
+    where
+        F: FnOnce(&mut ConnectionState) -> Result<T, MaxTouchError> + Send + 'static,
+        T: Send + 'static,
+    {
+        let (sender, receiver) = oneshot::channel();
+        let job: Job = Box::new(move |connection| {
+            // The caller may have gone away, nothing to do in that case.
+            let _ = sender.send(job(connection));
+        });
+
+        let mut queue = self.shared.queue.lock();
+        if !queue.shutdown {
+            let jobs = &mut queue.jobs[priority as usize];
+            if let Some(tag) = tag {
+                // Drop any requests which this one supersedes.
+                jobs.retain(|queued| queued.tag != Some(tag));
+            }
+            jobs.push_back(QueuedJob { tag, job });
+            self.shared.available.notify_one();
+        }
+        receiver
+    }
+
+    /// Run `job` on the I/O thread and wait for the result.
+    pub async fn call<T, F>(&self, priority: Priority, job: F) -> Result<T, MaxTouchError>
+    where
+        F: FnOnce(&mut ConnectionState) -> Result<T, MaxTouchError> + Send + 'static,
+        T: Send + 'static,
+    {
+        self.submit(priority, None, job).await.unwrap_or(Err(MaxTouchError::Cancelled))
+    }
+
+    /// Like `call`, but any queued requests with the same tag are cancelled.
+    /// Used for polled requests where only the latest result is interesting.
+    pub async fn call_latest<T, F>(&self, priority: Priority, tag: &'static str, job: F) -> Result<T, MaxTouchError>
+    where
+        F: FnOnce(&mut ConnectionState) -> Result<T, MaxTouchError> + Send + 'static,
+        T: Send + 'static,
+    {
+        self.submit(priority, Some(tag), job).await.unwrap_or(Err(MaxTouchError::Cancelled))
+    }
+
+    /// Blocking version of `call` for use from threads outside of the async runtime.
+    pub fn call_blocking<T, F>(&self, priority: Priority, job: F) -> Result<T, MaxTouchError>
+    where
+        F: FnOnce(&mut ConnectionState) -> Result<T, MaxTouchError> + Send + 'static,
+        T: Send + 'static,
+    {
+        self.submit(priority, None, job).blocking_recv().unwrap_or(Err(MaxTouchError::Cancelled))
+    }
+
+    /// Stop the I/O thread once the current request completes, cancelling
+    /// anything still queued.
+    pub fn shutdown(&self) {
+        self.shared.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::MemoryTransport;
+
+    #[test]
+    fn panicking_job_cancels_the_queue() {
+        let worker = DeviceWorker::spawn("test", ConnectionState::new(Box::new(MemoryTransport::new())));
+        assert_eq!(worker.call_blocking(Priority::Normal, |_| Ok(1)), Ok(1));
+
+        let result: Result<(), MaxTouchError> = worker.call_blocking(Priority::Normal, |_| panic!("job failed"));
+        assert_eq!(result, Err(MaxTouchError::Cancelled));
+        // Later requests fail straight away rather than waiting forever.
+        assert_eq!(worker.call_blocking(Priority::High, |_| Ok(2)), Err(MaxTouchError::Cancelled));
+    }
+}
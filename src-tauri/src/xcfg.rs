@@ -0,0 +1,225 @@
+//! Microchip's `.xcfg` format, an INI style file with a section per object
+//! instance:
+//!
+//! ```text
+//! [GEN_POWERCONFIG_T7 INSTANCE 0]
+//! OBJECT_ADDRESS=312
+//! OBJECT_SIZE=5
+//! 0 1 IDLEACQINT=32
+//! ```
+//!
+//! Register lines give the offset, width in bytes, name and decimal value.
+
+use crate::checksum::NON_CONFIG_OBJECTS;
+use crate::config_file::{self, ConfigFile, InstanceContents};
+use crate::error::MaxTouchError;
+use crate::layout;
+use crate::maxtouch::InformationBlock;
+use crate::ConnectionState;
+
+/// Section names Microchip uses, without the `_T<type>` suffix.
+pub(crate) fn object_name(object_type: u8) -> &'static str {
+    match object_type {
+        5 => "GEN_MESSAGEPROCESSOR",
+        6 => "GEN_COMMANDPROCESSOR",
+        7 => "GEN_POWERCONFIG",
+        8 => "GEN_ACQUISITIONCONFIG",
+        15 => "TOUCH_KEYARRAY",
+        18 => "SPT_COMMSCONFIG",
+        19 => "SPT_GPIOPWM",
+        25 => "SPT_SELFTEST",
+        37 => "DEBUG_DIAGNOSTIC",
+        38 => "SPT_USERDATA",
+        42 => "PROCI_TOUCHSUPPRESSION",
+        44 => "SPT_MESSAGECOUNT",
+        46 => "SPT_CTECONFIG",
+        47 => "PROCI_STYLUS",
+        56 => "PROCI_SHIELDLESS",
+        61 => "SPT_TIMER",
+        65 => "PROCI_LENSBENDING",
+        68 => "SPT_SERIALDATACOMMAND",
+        70 => "SPT_DYNAMICCONFIGURATIONCONTROLLER",
+        71 => "SPT_DYNAMICCONFIGURATIONCONTAINER",
+        72 => "PROCG_NOISESUPPRESSION",
+        78 => "PROCI_GLOVEDETECTION",
+        80 => "PROCI_RETRANSMISSIONCOMPENSATION",
+        100 => "TOUCH_MULTITOUCHSCREEN",
+        _ => "UNKNOWN",
+    }
+}
+
+pub fn write(config: &ConfigFile) -> String {
+    let info = &config.info;
+    let mut text = String::from("[COMMENTS]\n");
+    text += "Saved by maxtouch-debug\n";
+    text += "[APPLICATION_INFO_HEADER]\n";
+    text += "NAME=maxtouch-debug\n";
+    text += &format!("VERSION={}\n", env!("CARGO_PKG_VERSION"));
+    text += "[VERSION_INFO_HEADER]\n";
+    text += &format!("FAMILY_ID={}\n", info.family_id);
+    text += &format!("VARIANT={}\n", info.variant_id);
+    text += &format!("VERSION={}\n", info.version);
+    text += &format!("BUILD={}\n", info.build);
+    text += &format!("CHECKSUM=0x{:06X}\n", config.config_crc().unwrap_or(0));
+    text += &format!("INFO_BLOCK_CHECKSUM=0x{:06X}\n", config.info_crc());
+
+    for object in &config.objects {
+        if NON_CONFIG_OBJECTS.contains(&object.object_type) {
+            continue;
+        }
+        // Fields which run past the end of the object on this chip are left
+        // out, the rest of the bytes are written one at a time.
+        let fields: Vec<_> = layout::object_layout(object.object_type).unwrap_or_default()
+            .into_iter()
+            .filter(|field| field.offset + field.width as u16 <= object.size)
+            .collect();
+        for data in &object.data {
+            text += &format!("[{}_T{} INSTANCE {}]\n", object_name(object.object_type), object.object_type, data.instance);
+            text += &format!("OBJECT_ADDRESS={}\n", object.address + data.instance * object.size);
+            text += &format!("OBJECT_SIZE={}\n", object.size);
+            let mut offset = 0;
+            while offset < data.raw.len() {
+                match fields.iter().find(|field| field.offset as usize == offset) {
+                    Some(field) => {
+                        text += &format!("{} {} {}={}\n", offset, field.width, field.name.to_ascii_uppercase(),
+                            field.value(&data.raw).unwrap_or(0));
+                        offset += field.width as usize;
+                    }
+                    None => {
+                        text += &format!("{} 1 UNKNOWN[{}]={}\n", offset, offset, data.raw[offset]);
+                        offset += 1;
+                    }
+                }
+            }
+        }
+    }
+    text
+}
+
+fn parse_error(line: usize, message: &str) -> MaxTouchError {
+    MaxTouchError::Encoding(format!("xcfg line {}: {}", line + 1, message))
+}
+
+fn parse_number(line: usize, text: &str) -> Result<i64, MaxTouchError> {
+    let text = text.trim();
+    let value = match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        Some(hex) => i64::from_str_radix(hex, 16),
+        None => text.parse(),
+    };
+    value.map_err(|_| parse_error(line, &format!("bad number {}", text)))
+}
+
+/// Parse an object size or register offset. These are resized to before the
+/// contents reach `assemble`, so anything outside the 16 bit address space is
+/// rejected here rather than allocated.
+fn parse_size(line: usize, text: &str) -> Result<usize, MaxTouchError> {
+    let value = parse_number(line, text)?;
+    u16::try_from(value)
+        .map(usize::from)
+        .map_err(|_| parse_error(line, &format!("{} is out of range", value)))
+}
+
+/// Parse `[NAME_T<type> INSTANCE <instance>]`.
+fn parse_object_header(header: &str) -> Option<(u8, u16)> {
+    let mut words = header.split_whitespace();
+    let name = words.next()?;
+    let object_type = name.rsplit_once("_T")?.1.parse().ok()?;
+    let instance = match (words.next(), words.next()) {
+        (Some("INSTANCE"), Some(instance)) => instance.parse().ok()?,
+        _ => 0,
+    };
+    Some((object_type, instance))
+}
+
+/// Parse a file and map it onto the connected chip. The file only names the
+/// chip, the matrix size and object count are taken from the device.
+pub fn read(text: &str, connection: &ConnectionState) -> Result<ConfigFile, MaxTouchError> {
+    let device = connection.info.as_ref().ok_or(MaxTouchError::NotConnected)?;
+    let mut info = InformationBlock { family_id: 0, variant_id: 0, version: 0, build: 0, ..device.clone() };
+    let mut config_crc = None;
+    let mut contents: Vec<InstanceContents> = Vec::new();
+    // The section being read, either the version header or an object instance.
+    let mut in_version_header = false;
+    let mut current: Option<usize> = None;
+
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        if let Some(header) = line.strip_prefix('[').and_then(|line| line.strip_suffix(']')) {
+            in_version_header = header == "VERSION_INFO_HEADER";
+            current = parse_object_header(header).map(|(object_type, instance)| {
+                contents.push(InstanceContents { object_type, instance, data: Vec::new() });
+                contents.len() - 1
+            });
+            continue;
+        }
+        let Some((key, value)) = line.split_once('=') else {
+            continue;
+        };
+
+        if in_version_header {
+            match key.trim() {
+                "FAMILY_ID" => info.family_id = parse_number(number, value)? as u8,
+                "VARIANT" => info.variant_id = parse_number(number, value)? as u8,
+                "VERSION" => info.version = parse_number(number, value)? as u8,
+                "BUILD" => info.build = parse_number(number, value)? as u8,
+                "CHECKSUM" => config_crc = Some(parse_number(number, value)? as u32),
+                _ => {}
+            }
+            continue;
+        }
+        let Some(index) = current else {
+            continue;
+        };
+        let data = &mut contents[index].data;
+        let mut words = key.split_whitespace();
+        match (words.next(), words.next()) {
+            (Some("OBJECT_SIZE"), None) => data.resize(parse_size(number, value)?, 0),
+            (Some(offset), Some(width)) => {
+                let offset = parse_size(number, offset)?;
+                let width = parse_number(number, width)?;
+                if !(1..=4).contains(&width) {
+                    return Err(parse_error(number, &format!("unsupported width {}", width)));
+                }
+                let width = width as usize;
+                let bytes = parse_number(number, value)?.to_le_bytes();
+                if data.len() < offset + width {
+                    data.resize(offset + width, 0);
+                }
+                data[offset..offset + width].copy_from_slice(&bytes[..width]);
+            }
+            _ => {}
+        }
+    }
+
+    let config = config_file::assemble(connection, info, contents)?;
+    if let Some(config_crc) = config_crc {
+        if config.config_crc() != Some(config_crc) {
+            println!("Configuration checksum {:06X} in the file does not match its contents", config_crc);
+        }
+    }
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config_file::tests::assert_round_trip;
+    use crate::tests::simulator_connection;
+
+    #[test]
+    fn round_trip() {
+        assert_round_trip(write, read);
+    }
+
+    #[test]
+    fn sizes_outside_the_address_space_are_rejected() {
+        let connection = simulator_connection();
+        for register in ["OBJECT_SIZE=100000", "OBJECT_SIZE=-1", "70000 1 IDLEACQINT=1", "-4 1 IDLEACQINT=1"] {
+            let text = format!("[GEN_POWERCONFIG_T7 INSTANCE 0]\n{}\n", register);
+            assert!(matches!(read(&text, &connection), Err(MaxTouchError::Encoding(_))), "{}", register);
+        }
+    }
+}
@@ -1,5 +1,6 @@
 import { Ref, ref, watch } from "vue";
 import { invoke } from "@tauri-apps/api/core";
+import { session } from "./SessionService.ts";
 
 interface MapNode {
     key: string,
@@ -247,11 +248,18 @@ const T100Offsets : { [key: string] : number } = {
     jlmmovintth : 67
 }
 
-function readObject(id : number, name : string, offsets : { [key: string] : number }) {
-    (invoke("read_object", { id: id }) as Promise<string>).then((obj_str) => {
+interface ObjectInfo {
+    object_type: number,
+    address: number,
+    size: number,
+    instances: number
+}
+
+function readObject(id : number, instance : number, name : string, offsets : { [key: string] : number }) {
+    (invoke("read_object", { session: session.value, id: id, instance: instance }) as Promise<string>).then((obj_str) => {
         let obj = JSON.parse(obj_str);
         let obj_node : MapNode = {
-            key: 't'+id,
+            key: 't'+id+'.'+instance,
             data: {
                 name: name,
                 value: ref(),
@@ -264,7 +272,7 @@ function readObject(id : number, name : string, offsets : { [key: string] : numb
             let child : MapNode = { key: register, data: { name: register, value: value, readonly: offsets[register] < 0 }, children: [] };
             watch (value, async (newValue) => {
                 // TODO: Handle bitfields and u16
-                invoke("write_register", {id: id, offset: offsets[register], data: [newValue]}).then(() => {
+                invoke("write_register", {session: session.value, id: id, instance: instance, offset: offsets[register], data: [newValue]}).then(() => {
                 }).catch((e) => {
                     console.log(e);
                 });
@@ -275,19 +283,35 @@ function readObject(id : number, name : string, offsets : { [key: string] : numb
     });
 }
 
+const objects : [number, string, { [key: string] : number }][] = [
+    [7, 'T7 General Power Config', T7Offsets],
+    [8, 'T8 Acquisition Config', T8Offsets],
+    [25, 'T25 Self Test', T25Offsets],
+    [42, 'T42 Touch Suppression', T42Offsets],
+    [46, 'T46 CTE Config', T46Offsets],
+    [47, 'T47 Passive Stylus Config', T47Offsets],
+    [56, 'T56 Shieldless Config', T56Offsets],
+    [65, 'T65 Lens Bending Config', T65Offsets],
+    [80, 'T80 Retransmission Compensation', T80Offsets],
+    [100, 'T100 Multiple Touch Touchscreen', T100Offsets]
+];
+
 export const NodeService = {
     update() {
         registers.value = [];
-        readObject(7, 'T7 General Power Config', T7Offsets);
-        readObject(8, 'T8 Acquisition Config', T8Offsets);
-        readObject(25, 'T25 Self Test', T25Offsets);
-        readObject(42, 'T42 Touch Suppression', T42Offsets);
-        readObject(46, 'T46 CTE Config', T46Offsets);
-        readObject(47, 'T47 Passive Stylus Config', T47Offsets);
-        readObject(56, 'T56 Shieldless Config', T56Offsets);
-        readObject(65, 'T65 Lens Bending Config', T65Offsets);
-        readObject(80, 'T80 Retransmission Compensation', T80Offsets);
-        readObject(100, 'T100 Multiple Touch Touchscreen', T100Offsets);
+        (invoke("list_objects", { session: session.value }) as Promise<ObjectInfo[]>).then((table) => {
+            for (const [id, name, offsets] of objects) {
+                const object = table.find((object) => object.object_type === id);
+                if (object === undefined) {
+                    continue;
+                }
+                for (let instance = 0; instance < object.instances; instance++) {
+                    readObject(id, instance, object.instances > 1 ? name + ' [' + instance + ']' : name, offsets);
+                }
+            }
+        }).catch((e) => {
+            console.log(e);
+        });
     },
     getTreeTableNodesData() {
         return registers.value;
@@ -0,0 +1,5 @@
+import { ref } from "vue";
+
+// The session of the connected trackpad, set by the debug plot when it connects
+// and passed with every command so they still work with several devices attached.
+export const session = ref<string>();